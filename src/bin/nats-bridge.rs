@@ -1,10 +1,19 @@
 #![deny(clippy::all)]
 #![deny(clippy::pedantic)]
 
-use json;
-use std::sync::mpsc;
-use std::{env, process, thread, time};
+use nats_bridge::cipher::Cipher;
 use nats_bridge::nats;
+use nats_bridge::pubnub;
+use nats_bridge::socket::{log, Tls};
+use nats_bridge::subject;
+use openssl::base64;
+use openssl::rand::rand_bytes;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::{env, process, thread, time};
 
 // =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
 // Configuration via Environmental Variables
@@ -13,12 +22,64 @@ struct Configuration {
     pub nats_host: String,
     pub nats_subject: String,
     pub nats_subject_root: String,
+    pub nats_queue_group: String,
+    pub nats_user: String,
+    pub nats_password: String,
+    pub nats_auth_token: String,
+    pub nats_credentials: String,
+    pub nats_tls_ca_file: String,
+    pub nats_tls_cert_file: String,
+    pub nats_tls_key_file: String,
+    pub nats_tls_server_name: String,
     pub pubnub_host: String,
+    pub pubnub_tls: String,
     pub pubnub_channel: String,
     pub pubnub_channel_root: String,
     pub publish_key: String,
     pub subscribe_key: String,
     pub secret_key: String,
+    pub auth_key: String,
+    pub cipher_key: String,
+    pub cipher_random_iv: String,
+    pub nats_request_timeout: String,
+    pub nats_flush_interval: String,
+    pub nats_jetstream_stream: String,
+    pub nats_jetstream_source: String,
+    pub nats_jetstream_durable: String,
+    pub nats_kv_bucket: String,
+}
+
+// A message waiting to be published on PubNub
+struct Publication {
+    channel: String,
+    data: String,
+    meta: json::JsonValue,
+}
+
+// NATS reply subjects forwarded to devices and not yet answered
+// Devices may only reply to requests the bridge has sent them, once and
+// within `REPLY_TTL`, so a forged `reply_to` cannot reach other subjects
+#[derive(Default)]
+struct Replies {
+    subjects: Mutex<HashMap<String, time::Instant>>,
+}
+
+const REPLY_TTL: time::Duration = time::Duration::new(60, 0);
+
+impl Replies {
+    fn forwarded(&self, reply_to: &str) {
+        let mut subjects = self.subjects.lock().expect("Reply subjects");
+        subjects.retain(|_subject, sent| sent.elapsed() < REPLY_TTL);
+        subjects.insert(reply_to.into(), time::Instant::now());
+    }
+
+    fn answer(&self, reply_to: &str) -> bool {
+        let mut subjects = self.subjects.lock().expect("Reply subjects");
+        match subjects.remove(reply_to) {
+            Some(sent) => sent.elapsed() < REPLY_TTL,
+            None => false,
+        }
+    }
 }
 
 fn environment_variables() -> Configuration {
@@ -26,19 +87,140 @@ fn environment_variables() -> Configuration {
         nats_host: fetch_env_var("NATS_HOST"),
         nats_subject: fetch_env_var("NATS_SUBJECT"),
         nats_subject_root: fetch_env_var("NATS_SUBJECT_ROOT"),
-        pubnub_host: "psdsn.pubnub.com:80".into(),
+        nats_queue_group: fetch_optional_env_var("NATS_QUEUE_GROUP"),
+        nats_user: fetch_optional_env_var("NATS_USER"),
+        nats_password: fetch_optional_env_var("NATS_PASSWORD"),
+        nats_auth_token: fetch_optional_env_var("NATS_AUTH_TOKEN"),
+        nats_credentials: fetch_optional_env_var("NATS_CREDENTIALS"),
+        nats_tls_ca_file: fetch_optional_env_var("NATS_TLS_CA_FILE"),
+        nats_tls_cert_file: fetch_optional_env_var("NATS_TLS_CERT_FILE"),
+        nats_tls_key_file: fetch_optional_env_var("NATS_TLS_KEY_FILE"),
+        nats_tls_server_name: fetch_optional_env_var("NATS_TLS_SERVER_NAME"),
+        pubnub_host: fetch_env_var_or("PUBNUB_HOST", "psdsn.pubnub.com:443"),
+        pubnub_tls: fetch_env_var_or("PUBNUB_TLS", "true"),
         pubnub_channel: fetch_env_var("PUBNUB_CHANNEL"),
         pubnub_channel_root: fetch_env_var("PUBNUB_CHANNEL_ROOT"),
         publish_key: fetch_env_var("PUBNUB_PUBLISH_KEY"),
         subscribe_key: fetch_env_var("PUBNUB_SUBSCRIBE_KEY"),
         secret_key: fetch_env_var("PUBNUB_SECRET_KEY"),
+        auth_key: fetch_optional_env_var("PUBNUB_AUTH_KEY"),
+        cipher_key: fetch_optional_env_var("PUBNUB_CIPHER_KEY"),
+        cipher_random_iv: fetch_env_var_or(
+            "PUBNUB_CIPHER_RANDOM_IV",
+            "false",
+        ),
+        nats_request_timeout: fetch_env_var_or("NATS_REQUEST_TIMEOUT", "5"),
+        nats_flush_interval: fetch_env_var_or("NATS_FLUSH_INTERVAL", "0"),
+        nats_jetstream_stream: fetch_optional_env_var(
+            "NATS_JETSTREAM_STREAM",
+        ),
+        nats_jetstream_source: fetch_optional_env_var(
+            "NATS_JETSTREAM_SOURCE",
+        ),
+        nats_jetstream_durable: fetch_env_var_or(
+            "NATS_JETSTREAM_DURABLE",
+            "nats-bridge",
+        ),
+        nats_kv_bucket: fetch_optional_env_var("NATS_KV_BUCKET"),
+    }
+}
+
+impl Configuration {
+    fn nats_options(&self) -> nats::Options {
+        nats::Options {
+            name: "nats-bridge".into(),
+            user: self.nats_user.clone(),
+            password: self.nats_password.clone(),
+            auth_token: self.nats_auth_token.clone(),
+            credentials: self.nats_credentials.clone(),
+            tls: self.nats_tls(),
+            flush_interval: self.flush_interval(),
+            no_echo: true,
+            origin: origin().into(),
+            ..nats::Options::default()
+        }
+    }
+
+    // HTTPS unless explicitly turned off
+    fn pubnub_options(&self) -> pubnub::Options {
+        pubnub::Options {
+            tls: if flag("PUBNUB_TLS", &self.pubnub_tls) {
+                Some(Tls::default())
+            } else {
+                None
+            },
+            cipher: self.cipher(),
+            auth_key: self.auth_key.clone(),
+        }
+    }
+
+    // Messages are encrypted only when a cipher key is set
+    fn cipher(&self) -> Option<Cipher> {
+        if self.cipher_key.is_empty() {
+            return None;
+        }
+        let random_iv =
+            flag("PUBNUB_CIPHER_RANDOM_IV", &self.cipher_random_iv);
+        match Cipher::new(&self.cipher_key, random_iv) {
+            Ok(cipher) => Some(cipher),
+            Err(error) => {
+                eprintln!("Invalid 'PUBNUB_CIPHER_KEY' {error:?}");
+                process::exit(1);
+            }
+        }
+    }
+
+    fn pubnub_publish_client(
+        &self,
+    ) -> Result<pubnub::PublishClient, pubnub::Error> {
+        pubnub::PublishClient::new(
+            &self.pubnub_host,
+            &self.pubnub_channel_root,
+            &self.publish_key,
+            &self.subscribe_key,
+            &self.secret_key,
+            "nats-bridge",
+            &self.pubnub_options(),
+        )
+    }
+
+    // Seconds to wait for a NATS service to respond
+    fn request_timeout(&self) -> time::Duration {
+        let seconds = self.nats_request_timeout.parse().unwrap_or(5);
+        time::Duration::new(seconds, 0)
+    }
+
+    // Milliseconds to batch NATS publishes, zero writes each one at once
+    fn flush_interval(&self) -> time::Duration {
+        let millis = self.nats_flush_interval.parse().unwrap_or(0);
+        time::Duration::from_millis(millis)
+    }
+
+    // TLS is required when any TLS setting is present, otherwise it is
+    // only used when the server asks for it.
+    fn nats_tls(&self) -> Option<Tls> {
+        let tls = Tls {
+            ca_file: self.nats_tls_ca_file.clone(),
+            cert_file: self.nats_tls_cert_file.clone(),
+            key_file: self.nats_tls_key_file.clone(),
+            server_name: self.nats_tls_server_name.clone(),
+        };
+        if tls.ca_file.is_empty()
+            && tls.cert_file.is_empty()
+            && tls.key_file.is_empty()
+            && tls.server_name.is_empty()
+        {
+            None
+        } else {
+            Some(tls)
+        }
     }
 }
 
 impl std::fmt::Display for Configuration {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let channel = if self.pubnub_channel_root.is_empty() {
-            self.pubnub_channel.to_string()
+            self.pubnub_channel.clone()
         } else {
             format!(
                 "{root}.{channel}",
@@ -62,15 +244,46 @@ impl std::fmt::Display for Configuration {
     }
 }
 
+// Marks the NATS messages published by this bridge, the subscriber
+// drops them when they come back on an overlapping subject
+fn origin() -> &'static str {
+    static ORIGIN: OnceLock<String> = OnceLock::new();
+    ORIGIN.get_or_init(|| {
+        let mut bytes = [0u8; 8];
+        rand_bytes(&mut bytes).expect("Random Origin");
+        format!("{:016x}", u64::from_be_bytes(bytes))
+    })
+}
+
 fn fetch_env_var(name: &str) -> String {
     if let Ok(value) = env::var(name) {
         value
     } else {
-        eprintln!("Missing '{}' Environmental Variable", name);
+        eprintln!("Missing '{name}' Environmental Variable");
+        process::exit(1);
+    }
+}
+
+// Boolean settings accept `true` or `false` in any case
+fn flag(name: &str, value: &str) -> bool {
+    if value.eq_ignore_ascii_case("true") {
+        true
+    } else if value.eq_ignore_ascii_case("false") {
+        false
+    } else {
+        eprintln!("Invalid '{name}' expected 'true' or 'false'");
         process::exit(1);
     }
 }
 
+fn fetch_optional_env_var(name: &str) -> String {
+    env::var(name).unwrap_or_default()
+}
+
+fn fetch_env_var_or(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| default.into())
+}
+
 // =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
 // Main Loop
 // =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
@@ -78,182 +291,982 @@ fn main() {
     // Async Channels
     let (nats_message_tx, pubnub_publish_rx) = mpsc::channel();
     let (pubnub_message_tx, nats_publish_rx) = mpsc::channel();
+    let (nats_request_tx, nats_request_rx) = mpsc::channel();
+    let nats_reply_tx = nats_message_tx.clone();
+    let kv_publish_tx = nats_message_tx.clone();
+
+    // Set on SIGTERM or SIGINT
+    // The NATS Publisher stops once the PubNub Subscriber has
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let pubnub_stopped = Arc::new(AtomicBool::new(false));
+    let publisher_shutdown = pubnub_stopped.clone();
+    let pubnub_shutdown = shutdown.clone();
+    let pubnub_publisher_shutdown = shutdown.clone();
+    let subscriber_shutdown = shutdown.clone();
+    let kv_shutdown = shutdown.clone();
+    let requester_shutdown = shutdown.clone();
+
+    // Reply subjects the NATS Subscriber forwarded to devices
+    let replies = Arc::new(Replies::default());
+    let publisher_replies = replies.clone();
 
     // Receive PubNub Messages
     // Subscribe to PubNub messages
+    let (pubnub_done_tx, pubnub_done_rx) = mpsc::channel::<()>();
     let pubnub_subscriber_thread = thread::Builder::new()
         .name("PubNub Subscriber Thread".into())
-        .spawn(move || loop {
-            use nats_bridge::pubnub;
-
-            let config = environment_variables();
-            let host = &config.pubnub_host;
-            let root = &config.pubnub_channel_root;
-            let channel = &config.pubnub_channel;
-            let subscribe_key = &config.subscribe_key;
-            let secret_key = &config.secret_key;
-            let agent = "nats-bridge";
-
-            let mut pubnub = match pubnub::SubscribeClient::new(
-                host,
-                root,
-                channel,
-                subscribe_key,
-                secret_key,
-                agent,
-            ) {
-                Ok(pubnub) => pubnub,
-                Err(_error) => {
-                    thread::sleep(time::Duration::new(1, 0));
-                    continue;
-                }
-            };
-
-            loop {
-                let message = match pubnub.next_message() {
-                    Ok(message) => message,
-                    Err(_error) => continue,
-                };
-                pubnub_message_tx
-                    .send(message)
-                    .expect("NATS mpsc::channel channel write");
-            }
+        .spawn(move || {
+            pubnub_subscriber(&pubnub_message_tx, &pubnub_shutdown);
+            drop(pubnub_done_tx);
         });
 
     // Send PubNub Messages
-    // Publish as fast as possible
+    // Publish as fast as possible, until every sender is gone
     let pubnub_publisher_thread = thread::Builder::new()
         .name("PubNub Publisher Thread".into())
-        .spawn(move || loop {
-            use nats_bridge::pubnub;
-
-            let config = environment_variables();
-            let host = &config.pubnub_host;
-            let root = &config.pubnub_channel_root;
-            let publish_key = &config.publish_key;
-            let subscribe_key = &config.subscribe_key;
-            let secret_key = &config.secret_key;
-            let agent = "nats-bridge";
-
-            let mut pubnub = match pubnub::PublishClient::new(
-                host,
-                root,
-                publish_key,
-                subscribe_key,
-                secret_key,
-                agent,
-            ) {
-                Ok(pubnub) => pubnub,
-                Err(_error) => {
-                    thread::sleep(time::Duration::new(1, 0));
-                    continue;
-                }
-            };
-
-            // Message Receiver Loop
-            loop {
-                let message: nats::Message =
-                    pubnub_publish_rx.recv().expect("MPSC Channel Receiver");
-                let channel = &message.subject;
-                let data = &message.data;
-
-                // Retry Loop on Failure
-                loop {
-                    match pubnub.publish(channel, data) {
-                        Ok(_timetoken) => break,
-                        Err(_error) => {
-                            thread::sleep(time::Duration::new(1, 0))
-                        }
-                    };
-                }
-            }
+        .spawn(move || {
+            pubnub_publisher(&pubnub_publish_rx, &pubnub_publisher_shutdown);
         });
 
     // Send NATS Messages
     // Publish as fast as possible
     let nats_publisher_thread = thread::Builder::new()
         .name("NATS Publisher Thread".into())
-        .spawn(move || loop {
-            let config = environment_variables();
-            let host = &config.nats_host;
-            let root = &config.nats_subject_root;
-
-            let mut nats = match nats::PublishClient::new(host, root) {
-                Ok(nats) => nats,
-                Err(_error) => {
-                    thread::sleep(time::Duration::from_millis(1000));
-                    continue;
-                }
-            };
+        .spawn(move || {
+            nats_publisher(
+                &nats_publish_rx,
+                &nats_request_tx,
+                &publisher_replies,
+                &publisher_shutdown,
+            );
+        });
 
-            loop {
-                let message: nats_bridge::pubnub::Message =
-                    nats_publish_rx.recv().expect("MPSC Channel Receiver");
-                match nats.publish(message.channel, message.data) {
-                    Ok(()) => {}
-                    Err(_error) => {
-                        thread::sleep(time::Duration::from_millis(1000));
-                    }
-                };
-            }
+    // Send NATS Requests for devices
+    // Waiting for a response never holds up the NATS Publisher
+    let nats_requester_thread = thread::Builder::new()
+        .name("NATS Request Thread".into())
+        .spawn(move || {
+            nats_requester(
+                &nats_request_rx,
+                &nats_reply_tx,
+                &requester_shutdown,
+            );
         });
 
     // Receive NATS Messages
     // Subscribe as fast as possbile
+    // A JetStream source replaces the subscriptions when configured
     let nats_subscriber_thread = thread::Builder::new()
         .name("NATS Subscriber Thread".into())
-        .spawn(move || loop {
-            let config = environment_variables();
-            let host = &config.nats_host;
-            let root = &config.nats_subject_root;
-            let subject = &config.nats_subject;
-            let mut nats =
-                match nats::SubscribeClient::new(host, root, subject) {
-                    Ok(nats) => nats,
-                    Err(_error) => {
-                        thread::sleep(time::Duration::from_millis(1000));
-                        continue;
-                    }
-                };
-            loop {
-                // Get NATS Messages
-                let mut message = match nats.next_message() {
-                    Ok(message) => message,
-                    Err(_error) => continue,
-                };
-
-                // Convert to JSON String if not already JSON
-                let parsetest = json::parse(&message.data);
-                if parsetest.is_err() {
-                    message.data = json::stringify(message.data);
-                }
-
-                // Enqueue message to be placed on the WAN
-                nats_message_tx
-                    .send(message)
-                    .expect("NATS mpsc::channel subject write");
+        .spawn(move || {
+            if environment_variables().nats_jetstream_source.is_empty() {
+                nats_subscriber(
+                    &nats_message_tx,
+                    &replies,
+                    &subscriber_shutdown,
+                );
+            } else {
+                jetstream_source(&subscriber_shutdown);
             }
         });
 
+    // Watch NATS Key-Value changes
+    // Only when a bucket is configured
+    let kv_watcher_thread = thread::Builder::new()
+        .name("NATS KV Watcher Thread".into())
+        .spawn(move || kv_watcher(&kv_publish_tx, &kv_shutdown));
+
     // Print Follow-on Instructions
     let config = environment_variables();
-    println!("{{\"info\":\"Dashboard: {}\"}}", config);
+    println!("{{\"info\":\"Dashboard: {config}\"}}");
+
+    // Drain the NATS clients before exiting
+    wait_for_signal();
+    shutdown.store(true, Ordering::SeqCst);
+
+    // Messages from a long poll in progress still reach NATS
+    // A poll still waiting after the grace period is abandoned
+    match pubnub_done_rx.recv_timeout(SHUTDOWN_GRACE) {
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            let info = "PubNub Subscriber still waiting on a long poll";
+            log("nats-bridge", "Shutdown", info);
+        }
+        _ => pubnub_subscriber_thread
+            .expect("PubNub Subscriber thread builder join handle")
+            .join()
+            .expect("Joining PubNub Subscriber Thread"),
+    }
+    pubnub_stopped.store(true, Ordering::SeqCst);
 
     // The Threads Gather
-    pubnub_subscriber_thread
-        .expect("PubNub Subscriber thread builder join handle")
-        .join()
-        .expect("Joining PubNub Subscriber Thread");
-    pubnub_publisher_thread
-        .expect("PubNub Publisher thread builder join handle")
-        .join()
-        .expect("Joining PubNub Publisher Thread");
     nats_publisher_thread
         .expect("NATS Publisher thread builder join handle")
         .join()
         .expect("Joining NATS Publisher Thread");
+    nats_requester_thread
+        .expect("NATS Request thread builder join handle")
+        .join()
+        .expect("Joining NATS Request Thread");
     nats_subscriber_thread
         .expect("NATS Subscriber thread builder join handle")
         .join()
         .expect("Joining NATS Subscriber Thread");
+    kv_watcher_thread
+        .expect("NATS KV Watcher thread builder join handle")
+        .join()
+        .expect("Joining NATS KV Watcher Thread");
+
+    // Publishes queued by the NATS threads reach PubNub first
+    // Exiting ends a PubNub Subscriber left in a long poll
+    pubnub_publisher_thread
+        .expect("PubNub Publisher thread builder join handle")
+        .join()
+        .expect("Joining PubNub Publisher Thread");
+    process::exit(0);
+}
+
+// Time to finish PubNub requests in progress once shutting down
+const SHUTDOWN_GRACE: time::Duration = time::Duration::from_secs(5);
+
+fn wait_for_signal() {
+    let mut signals =
+        Signals::new([SIGTERM, SIGINT]).expect("Signal Handler Registration");
+    if let Some(signal) = signals.forever().next() {
+        let info = format!("Draining on signal {signal}");
+        log("nats-bridge", "Shutdown", &info);
+    }
+
+    // A second signal skips the drain
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            process::exit(1);
+        }
+    });
+}
+
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+// PubNub Subscriber Thread
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+fn pubnub_subscriber(
+    pubnub_message_tx: &mpsc::Sender<pubnub::Message>,
+    shutdown: &AtomicBool,
+) {
+    let mut backoff = Backoff::default();
+    while !shutdown.load(Ordering::SeqCst) {
+        let config = environment_variables();
+        let host = &config.pubnub_host;
+        let root = &config.pubnub_channel_root;
+        let channel = &config.pubnub_channel;
+        let publish_key = &config.publish_key;
+        let subscribe_key = &config.subscribe_key;
+        let secret_key = &config.secret_key;
+        let agent = "nats-bridge";
+        let options = config.pubnub_options();
+
+        let mut pubnub = match pubnub::SubscribeClient::new(
+            host,
+            root,
+            channel,
+            publish_key,
+            subscribe_key,
+            secret_key,
+            agent,
+            &options,
+        ) {
+            Ok(pubnub) => pubnub,
+            Err(error) => {
+                backoff.wait(host, &error, shutdown);
+                continue;
+            }
+        };
+
+        while !shutdown.load(Ordering::SeqCst) {
+            let message = match pubnub.next_message() {
+                Ok(message) => message,
+                Err(pubnub::Error::SubscribeRead) => continue,
+                Err(error) => {
+                    backoff.wait(host, &error, shutdown);
+                    continue;
+                }
+            };
+            backoff.reset();
+            // The NATS Publisher is gone after the grace period
+            if pubnub_message_tx.send(message).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// Longest wait between failed PubNub subscribes
+const MAX_BACKOFF: time::Duration = time::Duration::new(60, 0);
+
+// Waits after a failed subscribe, doubling up to `MAX_BACKOFF`
+// Rejected keys, permissions or channels fail again until the
+// configuration is fixed, so those wait the longest right away.
+#[derive(Default)]
+struct Backoff {
+    delay: Option<time::Duration>,
+}
+
+impl Backoff {
+    fn wait(
+        &mut self,
+        host: &str,
+        error: &pubnub::Error,
+        shutdown: &AtomicBool,
+    ) {
+        let delay = self.next(error);
+        let info = if error.is_transient() {
+            format!("{error:?} retrying in {}s", delay.as_secs())
+        } else {
+            format!(
+                "{error:?} will not succeed until the subscribe key, \
+                 secret key, auth key or channel is fixed, \
+                 retrying in {}s",
+                delay.as_secs()
+            )
+        };
+        log(host, "PubNub Subscriber", &info);
+
+        // Shutting down ends the wait
+        let until = time::Instant::now() + delay;
+        while !shutdown.load(Ordering::SeqCst) && time::Instant::now() < until
+        {
+            thread::sleep(time::Duration::from_millis(100));
+        }
+    }
+
+    fn next(&mut self, error: &pubnub::Error) -> time::Duration {
+        let delay = if error.is_transient() {
+            self.delay
+                .map_or(time::Duration::from_secs(1), |delay| delay * 2)
+                .min(MAX_BACKOFF)
+        } else {
+            MAX_BACKOFF
+        };
+        self.delay = Some(delay);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.delay = None;
+    }
+}
+
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+// PubNub Publisher Thread
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+// Retries end `SHUTDOWN_GRACE` after the shutdown is noticed
+fn grace_expired(shutdown: &AtomicBool) -> impl FnMut() -> bool + '_ {
+    let mut deadline = None;
+    move || {
+        if !shutdown.load(Ordering::SeqCst) {
+            return false;
+        }
+        let deadline = *deadline
+            .get_or_insert_with(|| time::Instant::now() + SHUTDOWN_GRACE);
+        time::Instant::now() >= deadline
+    }
+}
+
+fn pubnub_publisher(
+    pubnub_publish_rx: &mpsc::Receiver<Publication>,
+    shutdown: &AtomicBool,
+) {
+    let mut expired = grace_expired(shutdown);
+    loop {
+        let config = environment_variables();
+        let host = &config.pubnub_host;
+        let mut pubnub = match config.pubnub_publish_client() {
+            Ok(pubnub) => pubnub,
+            Err(_error) if expired() => return,
+            Err(_error) => {
+                thread::sleep(time::Duration::new(1, 0));
+                continue;
+            }
+        };
+
+        // Message Receiver Loop
+        loop {
+            let message: Publication = match pubnub_publish_rx.recv() {
+                Ok(message) => message,
+                Err(_disconnected) => return,
+            };
+            let channel = &message.channel;
+            let data = &message.data;
+            let meta = &message.meta;
+
+            // Retry Loop on Failure
+            // Messages PubNub rejects, like on invalid channels, are dropped
+            loop {
+                match pubnub.publish_with_meta(channel, data, meta) {
+                    Ok(_timetoken) => break,
+                    Err(error) if !error.is_transient() || expired() => {
+                        log(host, "PubNub Publisher", &format!("{error:?}"));
+                        break;
+                    }
+                    Err(_error) => thread::sleep(time::Duration::new(1, 0)),
+                }
+            }
+        }
+    }
+}
+
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+// NATS Publisher Thread
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+fn nats_publisher(
+    nats_publish_rx: &mpsc::Receiver<pubnub::Message>,
+    nats_request_tx: &mpsc::Sender<pubnub::Message>,
+    replies: &Replies,
+    shutdown: &AtomicBool,
+) {
+    while !shutdown.load(Ordering::SeqCst) {
+        let config = environment_variables();
+        let host = &config.nats_host;
+        let root = &config.nats_subject_root;
+        let options = config.nats_options();
+        let timeout = config.request_timeout();
+        let batching = !options.flush_interval.is_zero();
+
+        let mut nats = match nats::PublishClient::new(host, root, &options) {
+            Ok(nats) => nats,
+            Err(error) => {
+                log(host, "NATS Publisher", &format!("{error:?}"));
+                thread::sleep(time::Duration::new(1, 0));
+                continue;
+            }
+        };
+
+        loop {
+            // Publish what is already queued then drain on shutdown
+            let message = if shutdown.load(Ordering::SeqCst) {
+                if let Ok(message) = nats_publish_rx.try_recv() {
+                    message
+                } else {
+                    if let Err(error) = nats.drain() {
+                        log(host, "NATS Publisher", &format!("{error:?}"));
+                    }
+                    return;
+                }
+            } else if let Ok(message) = nats_publish_rx.try_recv() {
+                message
+            } else {
+                // Confirm the batched messages while the queue is empty
+                if batching {
+                    if let Err(error) = nats.flush() {
+                        log(host, "NATS Publisher", &format!("{error:?}"));
+                    }
+                }
+                match nats_publish_rx.recv_timeout(time::Duration::new(1, 0))
+                {
+                    Ok(message) => message,
+                    Err(_timeout) => continue,
+                }
+            };
+
+            // Device requests to NATS services carry a correlation id
+            let meta = json::parse(&message.metadata).unwrap_or(json::Null);
+            if !meta["correlation_id"].is_null() && !meta["device"].is_null()
+            {
+                match meta["device"].as_str() {
+                    Some(device) if is_valid_device(device) => {
+                        // The NATS Requester gives up once shutting down
+                        if nats_request_tx.send(message).is_err() {
+                            let info = format!("Dropped request of {device}");
+                            log(host, "NATS Requester", &info);
+                        }
+                    }
+                    _ => {
+                        let device = meta["device"].dump();
+                        let info = format!("Invalid device {device}");
+                        log(host, "NATS Publisher", &info);
+                    }
+                }
+                continue;
+            }
+
+            let stream = &config.nats_jetstream_stream;
+            let result = if meta["reply_to"].is_null() && !stream.is_empty() {
+                let headers = nats_headers(&meta["headers"]);
+                jetstream_publish(
+                    &mut nats, host, stream, &message, headers, timeout,
+                    shutdown,
+                );
+                Ok(())
+            } else {
+                nats_publish(&mut nats, message, &meta, replies)
+            };
+            // Messages the server would refuse are dropped
+            match result {
+                Ok(()) => {}
+                Err(
+                    error @ (nats::Error::PayloadTooLarge
+                    | nats::Error::ControlLineTooLong
+                    | nats::Error::InvalidSubject(_)),
+                ) => {
+                    log(host, "NATS Publisher", &format!("{error:?}"));
+                }
+                Err(error) => {
+                    log(host, "NATS Publisher", &format!("{error:?}"));
+                    thread::sleep(time::Duration::new(1, 0));
+                }
+            }
+        }
+    }
+}
+
+// Device responses to NATS requests go to the reply subject
+// Reply subjects the bridge did not forward are refused
+fn nats_publish(
+    nats: &mut nats::PublishClient,
+    message: pubnub::Message,
+    meta: &json::JsonValue,
+    replies: &Replies,
+) -> Result<(), nats::Error> {
+    let headers = nats_headers(&meta["headers"]);
+    match meta["reply_to"].as_str() {
+        Some(reply_to) if !replies.answer(reply_to) => {
+            Err(nats::Error::InvalidSubject(reply_to.into()))
+        }
+        Some(reply_to) => {
+            nats.reply_with_headers(reply_to, &headers, message.data)
+        }
+        None => {
+            nats.publish_with_headers(message.channel, &headers, message.data)
+        }
+    }
+}
+
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+// NATS Request Thread
+// Runs until the NATS Publisher stops sending requests
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+fn nats_requester(
+    nats_request_rx: &mpsc::Receiver<pubnub::Message>,
+    nats_reply_tx: &mpsc::Sender<Publication>,
+    shutdown: &AtomicBool,
+) {
+    let Ok(mut message) = nats_request_rx.recv() else {
+        return;
+    };
+    loop {
+        let config = environment_variables();
+        let host = &config.nats_host;
+        let root = &config.nats_subject_root;
+        let options = config.nats_options();
+        let timeout = config.request_timeout();
+
+        let mut nats = match nats::PublishClient::new(host, root, &options) {
+            Ok(nats) => nats,
+            Err(error) => {
+                log(host, "NATS Requester", &format!("{error:?}"));
+                if shutdown.load(Ordering::SeqCst) {
+                    return;
+                }
+                thread::sleep(time::Duration::new(1, 0));
+                continue;
+            }
+        };
+
+        // Requests reconnect on their own once connected
+        loop {
+            let reply = device_request(&mut nats, host, &message, timeout);
+            nats_reply_tx
+                .send(reply)
+                .expect("PubNub mpsc::channel reply write");
+            message = match nats_request_rx.recv() {
+                Ok(message) => message,
+                Err(_disconnected) => return,
+            };
+        }
+    }
+}
+
+// A device name is a single channel token, it picks the reply channel
+fn is_valid_device(device: &str) -> bool {
+    !device.contains('.')
+        && subject::is_valid(device)
+        && subject::is_valid_channel(device)
+}
+
+// Turn a device request into a NATS request
+// The response is published to the device's reply channel
+fn device_request(
+    nats: &mut nats::PublishClient,
+    host: &str,
+    message: &pubnub::Message,
+    timeout: time::Duration,
+) -> Publication {
+    let meta = json::parse(&message.metadata).unwrap_or(json::Null);
+    let correlation_id = &meta["correlation_id"];
+    let device = meta["device"].as_str().unwrap_or_default();
+
+    let response = nats.request(&message.channel, &message.data, timeout);
+    let data = match &response {
+        Ok(response) => json_data(&response.payload),
+        Err(error) => {
+            log(host, "NATS Request", &format!("{error:?}"));
+            json::stringify(json::object! {
+                "error" => format!("{error:?}")
+            })
+        }
+    };
+    let mut reply_meta = json::JsonValue::new_object();
+    reply_meta["correlation_id"] = correlation_id.clone();
+    if let Ok(response) = &response {
+        if !response.headers.is_empty() {
+            reply_meta["headers"] = json_headers(&response.headers);
+        }
+    }
+    Publication {
+        channel: format!("replies.{device}"),
+        data,
+        meta: reply_meta,
+    }
+}
+
+// Attempts to store a message before it is dropped
+const JETSTREAM_ATTEMPTS: u32 = 5;
+
+// Store the message in a JetStream stream, retrying until acknowledged
+// The PubNub timetoken lets the stream discard retried duplicates
+// Only a lost connection or a missing ack is retried, and not once
+// shutting down. Errors from the stream, like no stream listening on
+// the subject, would fail again and the message is dropped.
+fn jetstream_publish(
+    nats: &mut nats::PublishClient,
+    host: &str,
+    stream: &str,
+    message: &pubnub::Message,
+    mut headers: nats::Headers,
+    timeout: time::Duration,
+    shutdown: &AtomicBool,
+) {
+    headers.insert("Nats-Msg-Id".into(), vec![message.id.clone()]);
+    headers.insert("Nats-Expected-Stream".into(), vec![stream.into()]);
+
+    // Retry Loop on Failure
+    for attempt in 1..=JETSTREAM_ATTEMPTS {
+        match nats.jetstream_publish(
+            &message.channel,
+            &headers,
+            &message.data,
+            timeout,
+        ) {
+            Ok(ack) => {
+                if ack.duplicate {
+                    let info = format!("Duplicate {}", message.id);
+                    log(host, "NATS JetStream", &info);
+                }
+                return;
+            }
+            Err(
+                error @ (nats::Error::Timeout
+                | nats::Error::Request
+                | nats::Error::Publish),
+            ) => {
+                log(host, "NATS JetStream", &format!("{error:?}"));
+                if shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                if attempt < JETSTREAM_ATTEMPTS {
+                    thread::sleep(time::Duration::new(1, 0));
+                }
+            }
+            Err(error) => {
+                log(host, "NATS JetStream", &format!("{error:?}"));
+                break;
+            }
+        }
+    }
+    let info = format!("Dropped {}", message.id);
+    log(host, "NATS JetStream", &info);
+}
+
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+// NATS Subscriber Thread
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+fn nats_subscriber(
+    nats_message_tx: &mpsc::Sender<Publication>,
+    replies: &Replies,
+    shutdown: &AtomicBool,
+) {
+    while !shutdown.load(Ordering::SeqCst) {
+        let config = environment_variables();
+        let host = &config.nats_host;
+        let root = &config.nats_subject_root;
+        let options = config.nats_options();
+
+        let mut nats =
+            match nats::SubscribeClient::new(host, root, "", &options) {
+                Ok(nats) => nats,
+                Err(error) => {
+                    log(host, "NATS Subscriber", &format!("{error:?}"));
+                    thread::sleep(time::Duration::new(1, 0));
+                    continue;
+                }
+            };
+
+        // One connection multiplexes every subject in the list
+        // Bridges sharing a queue group split the messages between them
+        let queue = &config.nats_queue_group;
+        for subject in config.nats_subject.split(',') {
+            if let Err(error) = nats.queue_subscribe(subject.trim(), queue) {
+                log(host, "NATS Subscriber", &format!("{error:?}"));
+            }
+        }
+
+        // Wake up every second to check for shutdown
+        nats.set_timeout(1);
+
+        loop {
+            // Forward the messages already on their way then stop
+            if shutdown.load(Ordering::SeqCst) {
+                match nats.drain() {
+                    Ok(messages) => {
+                        for message in &messages {
+                            nats_message_tx
+                                .send(publication(message, replies))
+                                .expect("NATS mpsc::channel subject write");
+                        }
+                    }
+                    Err(error) => {
+                        log(host, "NATS Subscriber", &format!("{error:?}"));
+                    }
+                }
+                return;
+            }
+
+            // Get NATS Messages
+            let message = match nats.next_event() {
+                Ok(nats::Event::Message(message)) => message,
+                Ok(nats::Event::Info(info)) => {
+                    let servers = info.connect_urls.join(",");
+                    let ldm = info.lame_duck_mode;
+                    let info = format!("servers={servers} ldm={ldm}");
+                    log(host, "NATS Subscriber", &info);
+                    continue;
+                }
+                Ok(nats::Event::Ok) | Err(nats::Error::Timeout) => continue,
+                // Start over with the credentials from the environment
+                Err(nats::Error::AuthorizationViolation) => {
+                    log(host, "NATS Subscriber", "Authorization Violation");
+                    thread::sleep(time::Duration::new(1, 0));
+                    break;
+                }
+                Err(error) => {
+                    log(host, "NATS Subscriber", &format!("{error:?}"));
+                    continue;
+                }
+            };
+
+            // Enqueue message to be placed on the WAN
+            nats_message_tx
+                .send(publication(&message, replies))
+                .expect("NATS mpsc::channel subject write");
+        }
+    }
+}
+
+// Carry the NATS reply subject so devices can respond
+fn publication(message: &nats::Message, replies: &Replies) -> Publication {
+    let mut meta = json::JsonValue::new_object();
+    if !message.reply_to.is_empty() {
+        meta["reply_to"] = message.reply_to.as_str().into();
+        replies.forwarded(&message.reply_to);
+    }
+    if !message.headers.is_empty() {
+        meta["headers"] = json_headers(&message.headers);
+    }
+    Publication {
+        channel: message.subject.clone(),
+        data: json_data(&message.payload),
+        meta,
+    }
+}
+
+// Convert to JSON String if not already JSON
+// Payloads that are not UTF-8 are sent Base64 encoded
+fn json_data(payload: &[u8]) -> String {
+    match std::str::from_utf8(payload).ok() {
+        Some(data) if json::parse(data).is_ok() => data.to_string(),
+        Some(data) => json::stringify(data),
+        None => json::stringify(base64::encode_block(payload)),
+    }
+}
+
+// NATS headers travel in PubNub meta as a JSON object
+// A repeated header is an array of its values
+fn json_headers(headers: &nats::Headers) -> json::JsonValue {
+    let mut object = json::JsonValue::new_object();
+    for (name, values) in headers {
+        object[name.as_str()] = match values.as_slice() {
+            [value] => value.as_str().into(),
+            values => values.to_vec().into(),
+        };
+    }
+    object
+}
+
+// `Nats-*` headers steer the server and the bridge, such as JetStream
+// deduplication and the origin mark, devices may not set them
+fn nats_headers(object: &json::JsonValue) -> nats::Headers {
+    let text = |value: &json::JsonValue| match value.as_str() {
+        Some(value) => value.to_string(),
+        None => value.dump(),
+    };
+    object
+        .entries()
+        .filter(|(name, _value)| {
+            !name.to_ascii_lowercase().starts_with("nats-")
+        })
+        .map(|(name, value)| {
+            let values = if value.is_array() {
+                value.members().map(text).collect()
+            } else {
+                vec![text(value)]
+            };
+            (name.to_string(), values)
+        })
+        .collect()
+}
+
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+// JetStream Source Thread
+// Messages are acknowledged only once PubNub returns a timetoken,
+// anything not acknowledged is delivered again, even after a restart.
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+fn jetstream_source(shutdown: &AtomicBool) {
+    let mut expired = grace_expired(shutdown);
+    while !shutdown.load(Ordering::SeqCst) {
+        let config = environment_variables();
+        let host = &config.nats_host;
+        let subjects: Vec<&str> =
+            config.nats_subject.split(',').map(str::trim).collect();
+
+        let mut consumer = match nats::Consumer::new(
+            host,
+            &config.nats_subject_root,
+            &config.nats_jetstream_source,
+            &config.nats_jetstream_durable,
+            &subjects,
+            &config.nats_options(),
+        ) {
+            Ok(consumer) => consumer,
+            Err(error) => {
+                log(host, "NATS JetStream", &format!("{error:?}"));
+                thread::sleep(time::Duration::new(1, 0));
+                continue;
+            }
+        };
+        let mut pubnub = match config.pubnub_publish_client() {
+            Ok(pubnub) => pubnub,
+            Err(_error) => {
+                thread::sleep(time::Duration::new(1, 0));
+                continue;
+            }
+        };
+
+        // Unacknowledged messages are delivered again after a restart
+        loop {
+            if shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+            let message =
+                match consumer.next_message(time::Duration::new(1, 0)) {
+                    Ok(message) => message,
+                    Err(nats::Error::Timeout) => continue,
+                    Err(error) => {
+                        log(host, "NATS JetStream", &format!("{error:?}"));
+                        thread::sleep(time::Duration::new(1, 0));
+                        continue;
+                    }
+                };
+
+            // The reply subject acknowledges the message, devices never see it
+            let mut meta = json::JsonValue::new_object();
+            if !message.headers.is_empty() {
+                meta["headers"] = json_headers(&message.headers);
+            }
+            let data = json_data(&message.payload);
+
+            // Retry Loop on Failure
+            // Messages PubNub rejects are terminated, never acknowledged
+            let result = loop {
+                match pubnub.publish_with_meta(&message.subject, &data, &meta)
+                {
+                    Ok(_timetoken) => break consumer.ack(&message),
+                    Err(error) if !error.is_transient() => {
+                        log(host, "NATS JetStream", &format!("{error:?}"));
+                        let info = format!("Terminated {}", message.subject);
+                        log(host, "NATS JetStream", &info);
+                        break consumer.term(&message);
+                    }
+                    // Left unacknowledged, the consumer delivers it again
+                    Err(error) if expired() => {
+                        log(host, "NATS JetStream", &format!("{error:?}"));
+                        return;
+                    }
+                    Err(_error) => thread::sleep(time::Duration::new(1, 0)),
+                }
+            };
+            if let Err(error) = result {
+                log(host, "NATS JetStream", &format!("{error:?}"));
+            }
+        }
+    }
+}
+
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+// NATS KV Watcher Thread
+// Every key is published on startup, then each change as it happens.
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+fn kv_watcher(
+    kv_publish_tx: &mpsc::Sender<Publication>,
+    shutdown: &AtomicBool,
+) {
+    while !shutdown.load(Ordering::SeqCst) {
+        let config = environment_variables();
+        let host = &config.nats_host;
+        let bucket = &config.nats_kv_bucket;
+        if bucket.is_empty() {
+            return;
+        }
+
+        let options = config.nats_options();
+        let mut watcher = match nats::Watcher::new(host, bucket, &options) {
+            Ok(watcher) => watcher,
+            Err(error) => {
+                log(host, "NATS KV Watcher", &format!("{error:?}"));
+                thread::sleep(time::Duration::new(1, 0));
+                continue;
+            }
+        };
+
+        // A new watcher starts over with a fresh snapshot
+        loop {
+            if shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+            let entry = match watcher.next_entry(time::Duration::new(1, 0)) {
+                Ok(entry) => entry,
+                Err(nats::Error::Timeout) => continue,
+                Err(error) => {
+                    log(host, "NATS KV Watcher", &format!("{error:?}"));
+                    break;
+                }
+            };
+
+            // Deleted keys are sent as null with the operation in meta
+            let data = match entry.operation {
+                nats::Operation::Put => json_data(&entry.value),
+                _ => "null".into(),
+            };
+            let mut meta = json::JsonValue::new_object();
+            meta["kv_bucket"] = entry.bucket.as_str().into();
+            meta["kv_key"] = entry.key.as_str().into();
+            meta["kv_operation"] = entry.operation.as_str().into();
+            meta["kv_revision"] = entry.revision.into();
+
+            kv_publish_tx
+                .send(Publication {
+                    channel: format!("{bucket}.{}", entry.key),
+                    data,
+                    meta,
+                })
+                .expect("PubNub mpsc::channel KV write");
+        }
+        thread::sleep(time::Duration::new(1, 0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_forged_dropped() {
+        let replies = Replies::default();
+        replies.forwarded("_INBOX.a1b2");
+
+        // Never forwarded
+        assert!(!replies.answer("_INBOX.c3d4"));
+        assert!(!replies.answer("devices.a.commands"));
+
+        // Answered once
+        assert!(replies.answer("_INBOX.a1b2"));
+        assert!(!replies.answer("_INBOX.a1b2"));
+
+        // Clients may use their own inbox prefix
+        replies.forwarded("_REPLIES.svc.e5f6");
+        assert!(replies.answer("_REPLIES.svc.e5f6"));
+    }
+
+    #[test]
+    fn backoff_ok() {
+        let secs = time::Duration::from_secs;
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.next(&pubnub::Error::Subscribe), secs(1));
+        assert_eq!(backoff.next(&pubnub::Error::RateLimited), secs(2));
+        assert_eq!(backoff.next(&pubnub::Error::HTTPStatus(503)), secs(4));
+        for _ in 0..10 {
+            backoff.next(&pubnub::Error::RateLimited);
+        }
+        assert_eq!(backoff.next(&pubnub::Error::RateLimited), MAX_BACKOFF);
+
+        backoff.reset();
+        assert_eq!(backoff.next(&pubnub::Error::Subscribe), secs(1));
+
+        // Rejected requests wait the longest right away
+        backoff.reset();
+        let denied = pubnub::Error::AccessDenied("Forbidden".into());
+        assert_eq!(backoff.next(&denied), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn device_ok() {
+        assert!(is_valid_device("sensor-1"));
+        assert!(!is_valid_device(""));
+        assert!(!is_valid_device("a.b"));
+        assert!(!is_valid_device("*"));
+        assert!(!is_valid_device(">"));
+        assert!(!is_valid_device("a b"));
+        assert!(!is_valid_device("a/b"));
+    }
+
+    #[test]
+    fn headers_ok() {
+        let mut headers = nats::Headers::new();
+        headers.insert("Accept".into(), vec!["a".into(), "b".into()]);
+        headers.insert("Trace-Id".into(), vec!["1".into()]);
+        let object = json_headers(&headers);
+        assert_eq!(object.dump(), r#"{"Accept":["a","b"],"Trace-Id":"1"}"#);
+        assert_eq!(nats_headers(&object), headers);
+
+        let object = json::object! {
+            "Nats-Msg-Id" => "forged",
+            "nats-expected-stream" => "OTHER",
+            "Nats-Bridge-Origin" => "bridge-1",
+            "Priority" => 1,
+        };
+        let headers = nats_headers(&object);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["Priority"], ["1"]);
+    }
+
+    #[test]
+    fn replies_expired_dropped() {
+        let replies = Replies::default();
+        let sent = time::Instant::now()
+            .checked_sub(REPLY_TTL)
+            .expect("Instant before the TTL");
+        replies
+            .subjects
+            .lock()
+            .expect("Reply subjects")
+            .insert("_INBOX.old".into(), sent);
+        assert!(!replies.answer("_INBOX.old"));
+    }
 }
@@ -0,0 +1,170 @@
+use openssl::base64;
+use openssl::hash::{hash, MessageDigest};
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt, encrypt, Cipher as Aes};
+
+#[derive(Debug)]
+pub enum Error {
+    Encrypt,
+    Decrypt,
+}
+
+// IV of the legacy mode, shared by every PubNub SDK
+const LEGACY_IV: &[u8; 16] = b"0123456789012345";
+
+const IV_LENGTH: usize = 16;
+
+/// # PubNub Message Cipher
+///
+/// AES-256-CBC with PKCS7 padding, compatible with the PubNub SDKs
+/// configured with the same cipher key. The key is the first 32
+/// characters of the hex SHA-256 of the cipher key.
+/// Legacy mode uses a fixed IV, random IV mode prepends a new IV
+/// to every ciphertext. Messages are base64 encoded.
+///
+/// ```
+/// use nats_bridge::cipher::Cipher;
+///
+/// let cipher = Cipher::new("enigma", false).expect("Cipher");
+/// let encrypted = cipher.encrypt("\"yay!\"").expect("Encrypted");
+/// assert_eq!(encrypted, "Wi24KS4pcTzvyuGOHubiXg==");
+/// assert_eq!(cipher.decrypt(&encrypted).expect("Decrypted"), "\"yay!\"");
+/// ```
+#[derive(Clone)]
+pub struct Cipher {
+    key: Vec<u8>,
+    random_iv: bool,
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Cipher")
+            .field("key", &"<redacted>")
+            .field("random_iv", &self.random_iv)
+            .finish()
+    }
+}
+
+impl Cipher {
+    pub fn new(cipher_key: &str, random_iv: bool) -> Result<Self, Error> {
+        let digest =
+            match hash(MessageDigest::sha256(), cipher_key.as_bytes()) {
+                Ok(digest) => digest,
+                Err(_error) => return Err(Error::Encrypt),
+            };
+        let hex: String =
+            digest.iter().fold(String::new(), |mut hex, byte| {
+                hex.push_str(&format!("{:02x}", byte));
+                hex
+            });
+        Ok(Self {
+            key: hex.as_bytes()[..32].to_vec(),
+            random_iv,
+        })
+    }
+
+    pub fn encrypt(&self, message: &str) -> Result<String, Error> {
+        if !self.random_iv {
+            return self.encrypt_with_iv(message, LEGACY_IV);
+        }
+        let mut iv = [0u8; IV_LENGTH];
+        match rand_bytes(&mut iv) {
+            Ok(()) => self.encrypt_with_iv(message, &iv),
+            Err(_error) => Err(Error::Encrypt),
+        }
+    }
+
+    pub fn decrypt(&self, message: &str) -> Result<String, Error> {
+        let data = match base64::decode_block(message) {
+            Ok(data) => data,
+            Err(_error) => return Err(Error::Decrypt),
+        };
+        let (iv, data) = if self.random_iv {
+            if data.len() < IV_LENGTH {
+                return Err(Error::Decrypt);
+            }
+            data.split_at(IV_LENGTH)
+        } else {
+            (&LEGACY_IV[..], &data[..])
+        };
+        let plain =
+            match decrypt(Aes::aes_256_cbc(), &self.key, Some(iv), data) {
+                Ok(plain) => plain,
+                Err(_error) => return Err(Error::Decrypt),
+            };
+        match String::from_utf8(plain) {
+            Ok(text) => Ok(text),
+            Err(_error) => Err(Error::Decrypt),
+        }
+    }
+
+    // Random IV mode sends the IV ahead of the ciphertext
+    fn encrypt_with_iv(
+        &self,
+        message: &str,
+        iv: &[u8],
+    ) -> Result<String, Error> {
+        let data = match encrypt(
+            Aes::aes_256_cbc(),
+            &self.key,
+            Some(iv),
+            message.as_bytes(),
+        ) {
+            Ok(data) => data,
+            Err(_error) => return Err(Error::Encrypt),
+        };
+        if self.random_iv {
+            let mut payload = iv.to_vec();
+            payload.extend(data);
+            Ok(base64::encode_block(&payload))
+        } else {
+            Ok(base64::encode_block(&data))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_ok() {
+        let cipher = Cipher::new("enigma", false).expect("Cipher");
+        let message = "{\"hello\":\"world\"}";
+        let encrypted = cipher.encrypt(message).expect("Encrypted");
+        assert_eq!(encrypted, "VPBUKFwZx3Sab/2T8rSV30tRcDSQK5ZLmNRgTXGpBzc=");
+        assert_eq!(cipher.decrypt(&encrypted).expect("Decrypted"), message);
+    }
+
+    #[test]
+    fn random_iv_ok() {
+        let cipher = Cipher::new("enigma", true).expect("Cipher");
+        let iv: Vec<u8> = (0..16).collect();
+        let encrypted =
+            cipher.encrypt_with_iv("\"yay!\"", &iv).expect("Encrypted");
+        assert_eq!(encrypted, "AAECAwQFBgcICQoLDA0OD4ZV2mCJcwMRDfAj92ah3jQ=");
+        assert_eq!(
+            cipher.decrypt(&encrypted).expect("Decrypted"),
+            "\"yay!\""
+        );
+
+        // A new IV for every message
+        let first = cipher.encrypt("\"yay!\"").expect("Encrypted");
+        let second = cipher.encrypt("\"yay!\"").expect("Encrypted");
+        assert_ne!(first, second);
+        assert_eq!(cipher.decrypt(&second).expect("Decrypted"), "\"yay!\"");
+    }
+
+    #[test]
+    fn decrypt_error() {
+        let cipher = Cipher::new("enigma", false).expect("Cipher");
+        assert!(cipher.decrypt("not base64!").is_err());
+        assert!(cipher.decrypt("Wi24KS4pcTzvyuGO").is_err());
+
+        let other = Cipher::new("other", false).expect("Cipher");
+        assert!(other.decrypt("Wi24KS4pcTzvyuGOHubiXg==").is_err());
+
+        let random = Cipher::new("enigma", true).expect("Cipher");
+        assert!(random.decrypt("AAEC").is_err());
+    }
+}
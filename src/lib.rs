@@ -1,6 +1,9 @@
 #![cfg_attr(feature = "nightly", feature(external_doc))]
 #![cfg_attr(feature = "nightly", doc(include = "../readme.md"))]
 
+pub mod cipher;
 pub mod nats;
+pub mod nkeys;
 pub mod pubnub;
 pub mod socket;
+pub mod subject;
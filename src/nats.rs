@@ -1,11 +1,102 @@
-use crate::socket::Socket;
+use crate::nkeys::Credentials;
+use crate::socket::{self, Socket, Tls};
+use crate::subject;
+use json::JsonValue;
+use openssl::rand::rand_bytes;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 
+/// NATS message headers, every value of a repeated name in order.
+pub type Headers = BTreeMap<String, Vec<String>>;
+
+/// Header set on published messages, replies and requests when
+/// `Options::origin` is not empty.
+pub const ORIGIN_HEADER: &str = "Nats-Bridge-Origin";
+
+/// # NATS Message
+///
+/// `status` and `description` come from a header status line such as
+/// `NATS/1.0 404 No Messages`, they are empty on ordinary messages.
 pub struct Message {
     pub root: String,
     pub subject: String,
-    pub my_id: String,
-    pub sender_id: String,
-    pub data: String,
+    pub sid: String,
+    pub reply_to: String,
+    pub status: String,
+    pub description: String,
+    pub headers: Headers,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    /// ## Message Data
+    ///
+    /// The payload as text, or `None` when it is not valid UTF-8.
+    /// Use `payload` for the raw bytes.
+    ///
+    /// ```
+    /// use nats_bridge::nats::{Headers, Message};
+    ///
+    /// let message = Message {
+    ///     root: "".into(),
+    ///     subject: "demo".into(),
+    ///     sid: "1".into(),
+    ///     reply_to: "".into(),
+    ///     status: "".into(),
+    ///     description: "".into(),
+    ///     headers: Headers::new(),
+    ///     payload: b"KNOCK".to_vec(),
+    /// };
+    /// assert_eq!(message.data(), Some("KNOCK"));
+    /// ```
+    pub fn data(&self) -> Option<&str> {
+        std::str::from_utf8(&self.payload).ok()
+    }
+}
+
+/// # NATS Server Events
+///
+/// Everything the server sends to a subscriber besides `PING`.
+/// `Info` is an asynchronous INFO update, such as new cluster members
+/// in `connect_urls` or the server entering lame duck mode before it
+/// shuts down. `Ok` acknowledges a command in verbose mode.
+pub enum Event {
+    Message(Message),
+    Info(ServerInfo),
+    Ok,
+}
+
+/// # NATS Server Information
+///
+/// The INFO sent by the server when connecting and in later updates.
+/// Messages larger than `max_payload` are rejected by the clients with
+/// `Error::PayloadTooLarge`, the server would close the connection.
+/// `headers` tells whether the server accepts `HPUB`.
+#[derive(Clone, Debug, Default)]
+pub struct ServerInfo {
+    pub server_id: String,
+    pub version: String,
+    pub proto: u64,
+    pub max_payload: usize,
+    pub headers: bool,
+    pub connect_urls: Vec<String>,
+    pub lame_duck_mode: bool,
+}
+
+impl ServerInfo {
+    fn from_json(info: &JsonValue) -> Self {
+        Self {
+            server_id: info["server_id"].as_str().unwrap_or_default().into(),
+            version: info["version"].as_str().unwrap_or_default().into(),
+            proto: info["proto"].as_u64().unwrap_or(0),
+            max_payload: info["max_payload"]
+                .as_usize()
+                .unwrap_or(MAX_PAYLOAD),
+            headers: info["headers"].as_bool().unwrap_or(false),
+            connect_urls: connect_urls(info),
+            lame_duck_mode: info["ldm"].as_bool().unwrap_or(false),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -13,138 +104,676 @@ pub enum Error {
     Initialize,
     Publish,
     Subscribe,
+    Unsubscribe,
+    Request,
+    Timeout,
+    NoResponders,
+    JetStream(String),
     Ping,
     Exit,
+    AuthorizationViolation,
+    PermissionsViolation(String),
+    SlowConsumer,
+    Server(String),
+    Credentials,
+    Tls,
+    PayloadTooLarge,
+    ControlLineTooLong,
+    InvalidSubject(String),
+}
+
+/// # NATS Connection Options
+///
+/// Settings sent to the server with the `CONNECT` command.
+/// Leave the credentials empty when the server does not require them.
+/// Setting `tls` requires TLS even when the server INFO does not ask for it.
+/// Set `credentials` to the path of a `.creds` file for JWT authentication.
+/// A non-zero `flush_interval` lets the publisher coalesce the messages
+/// published within the interval into a single write.
+/// Nothing writes the buffer in the background, only the next publish
+/// once the interval has passed or `flush()` does. Callers must call
+/// `flush()` whenever they stop publishing, like when their queue of
+/// messages is empty, or the buffered messages are never sent.
+/// Set `no_echo` so a connection does not receive its own messages.
+/// Clients sharing an `origin` drop the messages published by each other,
+/// even over separate connections.
+///
+/// ```
+/// use nats_bridge::nats::Options;
+///
+/// let options = Options {
+///     name: "nats-bridge".into(),
+///     user: "user".into(),
+///     password: "password".into(),
+///     ..Options::default()
+/// };
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub name: String,
+    pub verbose: bool,
+    pub pedantic: bool,
+    pub user: String,
+    pub password: String,
+    pub auth_token: String,
+    pub credentials: String,
+    pub tls: Option<Tls>,
+    pub flush_interval: Duration,
+    pub no_echo: bool,
+    pub origin: String,
+}
+
+/// # JetStream Publish Acknowledgement
+///
+/// The stream that stored the message and its sequence number.
+/// `duplicate` is set when the `Nats-Msg-Id` header was seen before
+/// and the message was not stored again.
+#[derive(Debug)]
+pub struct PubAck {
+    pub stream: String,
+    pub sequence: u64,
+    pub duplicate: bool,
 }
 
+// Idle time before checking the connection with a PING
+const KEEPALIVE: Duration = Duration::from_secs(30);
+
+// Buffered publishes are written once they reach this size
+const MAX_BUFFER: usize = 64 * 1024;
+
+// Server defaults, the control line limit is not announced in INFO
+const MAX_PAYLOAD: usize = 1024 * 1024;
+const MAX_CONTROL_LINE: usize = 4096;
+
 pub struct SubscribeClient {
     socket: Socket,
-    client_id: String,
+    options: Options,
     root: String,
+    sid: u64,
+    last_read: Instant,
+    keepalive: Duration,
+    pings: usize,
+    info: ServerInfo,
+    subscriptions: BTreeMap<String, Subscription>,
+}
+
+struct Subscription {
     subject: String,
+    queue: String,
 }
 
 pub struct PublishClient {
     socket: Socket,
+    options: Options,
     root: String,
+    sid: u64,
+    info: ServerInfo,
+    buffer: Vec<u8>,
+    buffered: usize,
+    last_write: Instant,
+    unconfirmed: bool,
+    lost: bool,
+}
+
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+// NATS Handshake
+// Reads the server INFO, upgrades to TLS when either side requires it,
+// sends CONNECT and waits for the PONG that confirms the server accepted
+// our credentials.
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+fn handshake(
+    socket: &mut Socket,
+    options: &Options,
+) -> Result<ServerInfo, Error> {
+    // Server Information
+    let info_line = match socket.readln() {
+        Ok(line) => line,
+        Err(_) => return Err(Error::Initialize),
+    };
+    let data = match info_line.trim().split_once(' ') {
+        Some(("INFO", data)) => data,
+        _ => return Err(Error::Initialize),
+    };
+    let info = match json::parse(data) {
+        Ok(info) => info,
+        Err(_) => return Err(Error::Initialize),
+    };
+
+    // Other cluster members to fail over to
+    socket.add_servers(&connect_urls(&info));
+
+    // Upgrade to TLS before sending credentials
+    let tls_required = info["tls_required"].as_bool().unwrap_or(false);
+    let tls = match &options.tls {
+        Some(tls) => Some(tls.clone()),
+        None if tls_required => Some(Tls::default()),
+        None => None,
+    };
+    if let Some(tls) = &tls {
+        if socket.upgrade(tls).is_err() {
+            return Err(Error::Tls);
+        }
+    }
+
+    // Client Information and Credentials
+    let mut connect = json::object! {
+        "verbose" => options.verbose,
+        "pedantic" => options.pedantic,
+        "tls_required" => tls.is_some(),
+        "name" => options.name.as_str(),
+        "lang" => "rust",
+        "version" => env!("CARGO_PKG_VERSION"),
+        "protocol" => 1,
+        "headers" => true,
+        "no_responders" => true,
+        "echo" => !options.no_echo,
+    };
+    let mut redacted = connect.clone();
+    if !options.user.is_empty() {
+        connect["user"] = options.user.as_str().into();
+        connect["pass"] = options.password.as_str().into();
+        redacted["user"] = options.user.as_str().into();
+        redacted["pass"] = "********".into();
+    }
+    if !options.auth_token.is_empty() {
+        connect["auth_token"] = options.auth_token.as_str().into();
+        redacted["auth_token"] = "********".into();
+    }
+
+    // Prove ownership of the user JWT by signing the server nonce
+    // Read on every connect to pick up rotated credentials
+    if !options.credentials.is_empty() {
+        let credentials = match Credentials::from_file(&options.credentials) {
+            Ok(credentials) => credentials,
+            Err(error) => {
+                socket.log(&format!("Credentials: {:?}", error));
+                return Err(Error::Credentials);
+            }
+        };
+        let nonce = info["nonce"].as_str().unwrap_or_default();
+        let signature = match credentials.sign(nonce) {
+            Ok(signature) => signature,
+            Err(_error) => return Err(Error::Credentials),
+        };
+        connect["jwt"] = credentials.jwt.as_str().into();
+        connect["sig"] = signature.into();
+        redacted["jwt"] = "********".into();
+        redacted["sig"] = "********".into();
+    }
+
+    // Never write credentials to the log
+    socket.log(&format!("CONNECT {}", json::stringify(redacted)));
+    let command = format!("CONNECT {}\r\nPING\r\n", json::stringify(connect));
+    if socket.send(command.as_bytes()).is_err() {
+        return Err(Error::Initialize);
+    }
+
+    // Wait for PONG or a rejection
+    loop {
+        let line = match socket.readln() {
+            Ok(line) => line,
+            Err(_) => return Err(Error::Initialize),
+        };
+        let line = line.trim();
+        if line == "PONG" {
+            return Ok(ServerInfo::from_json(&info));
+        }
+        if line.starts_with("-ERR") {
+            socket.log(line);
+            return match server_error(line) {
+                Error::AuthorizationViolation => {
+                    Err(Error::AuthorizationViolation)
+                }
+                _ => Err(Error::Initialize),
+            };
+        }
+    }
+}
+
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+// NATS Message Reader
+// MSG <subject> <sid> [reply-to] <#bytes>\r\n[payload]\r\n
+// HMSG <subject> <sid> [reply-to] <#header bytes> <#total bytes>\r\n
+//     [headers]\r\n\r\n[payload]\r\n
+// The payload is read by its advertised length so it may hold any bytes.
+// Returns `None` when the stream can no longer be trusted.
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+fn read_msg(socket: &mut Socket, detail: &[&str]) -> Option<Message> {
+    let (reply_to, header_length, length) = match detail {
+        ["MSG", _, _, length] => ("", "0", length),
+        ["MSG", _, _, reply_to, length] => (*reply_to, "0", length),
+        ["HMSG", _, _, header_length, length] => ("", *header_length, length),
+        ["HMSG", _, _, reply_to, header_length, length] => {
+            (*reply_to, *header_length, length)
+        }
+        _ => {
+            socket.disconnect();
+            return None;
+        }
+    };
+    let (header_length, length): (usize, usize) =
+        match (header_length.parse(), length.parse()) {
+            (Ok(header_length), Ok(length)) if header_length <= length => {
+                (header_length, length)
+            }
+            _ => {
+                socket.disconnect();
+                return None;
+            }
+        };
+
+    let mut payload = match socket.read_exact(length + 2) {
+        Ok(payload) => payload,
+        Err(_) => return None,
+    };
+    payload.truncate(length);
+    let (status, description, headers) =
+        parse_headers(&payload[..header_length]);
+    let payload = payload.split_off(header_length);
+
+    Some(Message {
+        root: "".into(),
+        subject: detail[1].into(),
+        sid: detail[2].into(),
+        reply_to: reply_to.into(),
+        status,
+        description,
+        headers,
+        payload,
+    })
+}
+
+// NATS/1.0[ <status>[ <description>]]\r\n<name>: <value>\r\n...\r\n
+// Returns the status, the description and the headers
+fn parse_headers(block: &[u8]) -> (String, String, Headers) {
+    let mut headers = Headers::new();
+    let block = String::from_utf8_lossy(block);
+    let mut lines = block.split("\r\n");
+
+    // Status line
+    let (mut code, mut description) = (String::new(), String::new());
+    if let Some(status) = lines.next() {
+        let mut status = status.splitn(3, ' ').skip(1);
+        code = status.next().unwrap_or_default().into();
+        description = status.next().unwrap_or_default().trim().into();
+    }
+
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            headers
+                .entry(name.trim().into())
+                .or_default()
+                .push(value.trim().into());
+        }
+    }
+    (code, description, headers)
+}
+
+// Header block sent before the payload of HPUB
+fn header_block(headers: &Headers) -> String {
+    let mut block = String::from("NATS/1.0\r\n");
+    for (name, values) in headers {
+        for value in values {
+            block.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    block.push_str("\r\n");
+    block
+}
+
+// -ERR '<message>'
+fn server_error(line: &str) -> Error {
+    let message = line
+        .trim()
+        .trim_start_matches("-ERR")
+        .trim()
+        .trim_matches('\'')
+        .to_string();
+    if message.starts_with("Authorization Violation") {
+        Error::AuthorizationViolation
+    } else if message.starts_with("Permissions Violation") {
+        Error::PermissionsViolation(message)
+    } else if message.starts_with("Slow Consumer") {
+        Error::SlowConsumer
+    } else if message.starts_with("Maximum Payload Violation") {
+        Error::PayloadTooLarge
+    } else {
+        Error::Server(message)
+    }
+}
+
+// Asynchronous INFO update
+fn info_update(line: &str) -> Option<ServerInfo> {
+    match line.trim().split_once(' ') {
+        Some(("INFO", data)) => {
+            Some(ServerInfo::from_json(&json::parse(data).ok()?))
+        }
+        _ => None,
+    }
+}
+
+fn connect_urls(info: &JsonValue) -> Vec<String> {
+    info["connect_urls"]
+        .members()
+        .filter_map(JsonValue::as_str)
+        .map(String::from)
+        .collect()
+}
+
+// Unique subject for request responses
+fn inbox() -> String {
+    let mut bytes = [0u8; 11];
+    rand_bytes(&mut bytes).expect("Random Inbox");
+    let token: String =
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("_INBOX.{}", token)
 }
 
 // =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
 /// # NATS Subscribe Client
 ///
 /// This client lib offers subscribe support to NATS.
+/// Any number of subjects share one connection, each with its own `sid`.
 ///
 /// ```no_run
-/// use nats_bridge::nats::SubscribeClient;
+/// use nats_bridge::nats::{Options, SubscribeClient};
 ///
 /// let root = "subjects"; // subjects.demo
 /// let subject = "demo";  // subjects.demo
-/// let mut nats = SubscribeClient::new("0.0.0.0:4222", root, subject)
-///     .expect("NATS Subscribe Client");
+/// let options = Options::default();
+/// let mut nats =
+///     SubscribeClient::new("0.0.0.0:4222", root, subject, &options)
+///         .expect("NATS Subscribe Client");
 ///
 /// let result = nats.next_message();
 /// assert!(result.is_ok());
 /// let message = result.expect("Received Message");
-/// println!("{} -> {}", message.subject, message.data);
+/// println!("{} -> {:?}", message.subject, message.data());
 /// ```
 // =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
 impl SubscribeClient {
-    pub fn new(host: &str, root: &str, subject: &str) -> Result<Self, Error> {
+    pub fn new(
+        host: &str,
+        root: &str,
+        subject: &str,
+        options: &Options,
+    ) -> Result<Self, Error> {
         let mut socket = Socket::new(host, "NATS Subscriber", 30);
-
-        // Get Client ID
-        let info_line = match socket.readln() {
-            Ok(line) => line,
-            Err(_) => return Err(Error::Initialize),
-        };
-        let data = match info_line.trim().split_whitespace().nth(1) {
-            Some(data) => data,
-            None => return Err(Error::Initialize),
-        };
-        let info = match json::parse(data) {
-            Ok(info) => info,
-            Err(_) => return Err(Error::Initialize),
-        };
-        let client_id = info["client_id"].to_string();
+        let info = handshake(&mut socket, options)?;
 
         let mut nats = Self {
             socket,
-            client_id,
+            options: options.clone(),
             root: root.into(),
-            subject: subject.into(),
+            sid: 0,
+            last_read: Instant::now(),
+            keepalive: KEEPALIVE,
+            pings: 0,
+            info,
+            subscriptions: BTreeMap::new(),
         };
 
-        nats.subscribe();
+        // An empty subject starts without subscriptions
+        if !subject.is_empty() {
+            nats.subscribe(subject)?;
+        }
         Ok(nats)
     }
 
-    /// ## Receive NATS Messages
+    /// ## Subscribe to NATS Subjects
     ///
-    /// Subscribe to any NATS subject.
-    /// > Warning: This method can only be called once per client because
-    /// > NATS does not support multiplexing.
-    /// > If you need multiple subjects, initialize one NATS client per
-    /// > subject and put each client into a thread.
+    /// Add a subscription to the shared connection.
+    /// The returned `sid` is set on every message for this subscription.
     ///
     /// ```no_run
-    /// use nats_bridge::nats::SubscribeClient;
+    /// use nats_bridge::nats::{Options, SubscribeClient};
     ///
     /// let root = "subjects"; // subjects.demo
-    /// let subject = "demo";  // subjects.demo
-    /// let mut nats = SubscribeClient::new("0.0.0.0:4222", root, subject)
+    /// let options = Options::default();
+    /// let mut nats = SubscribeClient::new("0.0.0.0:4222", root, "", &options)
     ///     .expect("NATS Subscribe Client");
     ///
+    /// let demo = nats.subscribe("demo").expect("Subscribed");
+    /// let other = nats.subscribe("other").expect("Subscribed");
+    ///
     /// let message = nats.next_message().expect("Received Message");
+    /// if message.sid == demo {
+    ///     println!("demo -> {:?}", message.data());
+    /// }
     /// ```
-    fn subscribe(&mut self) {
-        loop {
-            let subject = if self.root.is_empty() {
-                self.subject.to_string()
-            } else {
-                format!(
-                    "{root}.{subject}",
-                    subject = self.subject,
-                    root = self.root
-                )
-            };
+    pub fn subscribe(&mut self, subject: &str) -> Result<String, Error> {
+        self.queue_subscribe(subject, "")
+    }
+
+    /// ## Subscribe with a Queue Group
+    ///
+    /// Subscribers sharing a queue group split the messages between them,
+    /// each message is delivered to only one member of the group.
+    /// Use this to scale out bridges without duplicating traffic.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Options, SubscribeClient};
+    ///
+    /// let options = Options::default();
+    /// let mut nats = SubscribeClient::new("0.0.0.0:4222", "", "", &options)
+    ///     .expect("NATS Subscribe Client");
+    ///
+    /// let sid = nats.queue_subscribe("demo", "bridges").expect("Subscribed");
+    /// let message = nats.next_message().expect("Received Message");
+    /// ```
+    pub fn queue_subscribe(
+        &mut self,
+        subject: &str,
+        queue: &str,
+    ) -> Result<String, Error> {
+        if subject.is_empty() {
+            return Err(Error::Subscribe);
+        }
+
+        // A malformed SUB would be refused by the server
+        let rooted = if self.root.is_empty() {
+            subject.to_string()
+        } else {
+            format!("{}.{}", self.root, subject)
+        };
+        if !subject::is_valid_pattern(&rooted) {
+            return Err(Error::InvalidSubject(rooted));
+        }
+        if !queue.is_empty() && !subject::is_valid(queue) {
+            return Err(Error::InvalidSubject(queue.into()));
+        }
+
+        self.sid += 1;
+        let sid = self.sid.to_string();
+        self.subscriptions.insert(
+            sid.clone(),
+            Subscription {
+                subject: subject.into(),
+                queue: queue.into(),
+            },
+        );
+
+        // Subscriptions are restored with the connection
+        if self.sub(&sid).is_err() {
+            self.resubscribe()?;
+        }
+        Ok(sid)
+    }
+
+    /// ## Unsubscribe from NATS Subjects
+    ///
+    /// Remove a subscription by the `sid` returned from `subscribe()`.
+    /// Messages already in flight for the `sid` are discarded.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Options, SubscribeClient};
+    ///
+    /// let options = Options::default();
+    /// let mut nats = SubscribeClient::new("0.0.0.0:4222", "", "", &options)
+    ///     .expect("NATS Subscribe Client");
+    ///
+    /// let sid = nats.subscribe("demo").expect("Subscribed");
+    /// nats.unsubscribe(&sid).expect("Unsubscribed");
+    /// ```
+    pub fn unsubscribe(&mut self, sid: &str) -> Result<(), Error> {
+        if self.subscriptions.remove(sid).is_none() {
+            return Err(Error::Unsubscribe);
+        }
+
+        let unsub = format!("UNSUB {sid}\r\n", sid = sid);
+        if self.socket.write(unsub).is_err() {
+            self.resubscribe()?;
+        }
+        Ok(())
+    }
 
-            let sub = format!(
-                "SUB {subject} {client_id}\r\n",
+    fn sub(&mut self, sid: &str) -> Result<(), Error> {
+        let subscription = match self.subscriptions.get(sid) {
+            Some(subscription) => subscription,
+            None => return Err(Error::Subscribe),
+        };
+        let subject = if self.root.is_empty() {
+            subscription.subject.to_string()
+        } else {
+            format!(
+                "{root}.{subject}",
+                subject = subscription.subject,
+                root = self.root
+            )
+        };
+
+        let sub = if subscription.queue.is_empty() {
+            format!("SUB {subject} {sid}\r\n", subject = subject, sid = sid)
+        } else {
+            format!(
+                "SUB {subject} {queue} {sid}\r\n",
                 subject = subject,
-                client_id = self.client_id,
-            );
-            match self.socket.write(sub) {
-                Ok(_) => break,
-                Err(_) => self.subscribe(),
-            };
+                queue = subscription.queue,
+                sid = sid,
+            )
+        };
+        match self.socket.write(sub) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::Subscribe),
+        }
+    }
+
+    // Reconnect when needed and send SUB for every subscription
+    // Rejected credentials will not get better by retrying
+    fn resubscribe(&mut self) -> Result<(), Error> {
+        'connection: loop {
+            // Repeat the handshake after losing the connection
+            if !self.socket.is_connected() {
+                self.socket.reconnect();
+                match handshake(&mut self.socket, &self.options) {
+                    Ok(info) => {
+                        self.info = info;
+                        self.pings = 0;
+                    }
+                    Err(Error::AuthorizationViolation) => {
+                        self.socket.disconnect();
+                        return Err(Error::AuthorizationViolation);
+                    }
+                    Err(_error) => continue,
+                }
+            }
+
+            let sids: Vec<String> =
+                self.subscriptions.keys().cloned().collect();
+            for sid in sids {
+                if self.sub(&sid).is_err() {
+                    continue 'connection;
+                }
+            }
+            return Ok(());
         }
     }
 
     /// ## Receive NATS Messages
     ///
-    /// Easy way to get messages from the initialized subject.
+    /// Easy way to get messages from all subscriptions.
+    /// Dispatch on `message.sid` to tell subscriptions apart.
     ///
     /// ```no_run
-    /// use nats_bridge::nats::SubscribeClient;
+    /// use nats_bridge::nats::{Options, SubscribeClient};
     ///
     /// let root = "subjects"; // subjects.demo
     /// let subject = "demo";  // subjects.demo
-    /// let mut nats = SubscribeClient::new("0.0.0.0:4222", root, subject)
-    ///     .expect("NATS Subscribe Client");
+    /// let options = Options::default();
+    /// let mut nats =
+    ///     SubscribeClient::new("0.0.0.0:4222", root, subject, &options)
+    ///         .expect("NATS Subscribe Client");
     ///
     /// let message = nats.next_message().expect("Received Message");
     /// ```
     pub fn next_message(&mut self) -> Result<Message, Error> {
+        loop {
+            if let Event::Message(message) = self.next_event()? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// ## Receive NATS Server Events
+    ///
+    /// Messages along with INFO updates and acknowledgements.
+    /// Server `-ERR` responses are returned as errors, the server closes
+    /// the connection after most of them and the next call reconnects.
+    /// A reconnect refused with `AuthorizationViolation` is returned
+    /// instead of retried, the next call tries again.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Error, Event, Options, SubscribeClient};
+    ///
+    /// let options = Options::default();
+    /// let mut nats = SubscribeClient::new("0.0.0.0:4222", "", "demo", &options)
+    ///     .expect("NATS Subscribe Client");
+    ///
+    /// match nats.next_event() {
+    ///     Ok(Event::Message(message)) => println!("{:?}", message.data()),
+    ///     Ok(Event::Info(info)) if info.lame_duck_mode => println!("LDM"),
+    ///     Ok(_) => {}
+    ///     Err(Error::SlowConsumer) => println!("Falling behind"),
+    ///     Err(error) => println!("{:?}", error),
+    /// }
+    /// ```
+    pub fn next_event(&mut self) -> Result<Event, Error> {
+        // Left disconnected by a rejected reconnect
+        if !self.socket.is_connected() {
+            self.resubscribe()?;
+        }
+
         loop {
             let data = match self.socket.readln() {
                 Ok(data) => data,
+                Err(socket::Error::Timeout) => {
+                    // Nothing received for a while, check the connection
+                    // A PING still unanswered means the server is gone
+                    if self.last_read.elapsed() >= self.keepalive {
+                        self.last_read = Instant::now();
+                        if self.pings > 0 {
+                            self.socket.log("Missed PONG");
+                            self.socket.disconnect();
+                            self.resubscribe()?;
+                        } else if self.socket.write("PING\r\n").is_err() {
+                            self.resubscribe()?;
+                        } else {
+                            self.pings += 1;
+                        }
+                    }
+                    return Err(Error::Timeout);
+                }
                 Err(_) => {
-                    self.subscribe();
+                    self.resubscribe()?;
                     return Err(Error::Subscribe);
                 }
             };
 
-            let detail: Vec<_> = data.trim().split_whitespace().collect();
+            // Anything from the server answers the keepalive
+            self.last_read = Instant::now();
+            self.pings = 0;
+
+            let detail: Vec<_> = data.split_whitespace().collect();
             if detail.is_empty() {
                 continue;
             }
@@ -154,41 +783,145 @@ impl SubscribeClient {
                 "PING" => {
                     match self.socket.write("PONG\r\n") {
                         Ok(_) => {}
-                        Err(_) => self.subscribe(),
+                        Err(_) => self.resubscribe()?,
                     };
                 }
-                "MSG" => {
-                    if detail.len() != 4 {
-                        continue;
+                "+OK" => return Ok(Event::Ok),
+                "-ERR" => {
+                    self.socket.log(data.trim());
+                    return Err(server_error(&data));
+                }
+                "INFO" => {
+                    if let Some(info) = info_update(&data) {
+                        self.socket.add_servers(&info.connect_urls);
+                        self.info = info.clone();
+                        return Ok(Event::Info(info));
                     }
-
-                    let message = match self.socket.readln() {
-                        Ok(message) => message,
-                        Err(_) => {
-                            self.subscribe();
+                }
+                "MSG" | "HMSG" => {
+                    let message = match read_msg(&mut self.socket, &detail) {
+                        Some(message) => message,
+                        None => {
+                            self.resubscribe()?;
                             return Err(Error::Subscribe);
                         }
                     };
+                    if let Some(message) = self.received(message) {
+                        return Ok(Event::Message(message));
+                    }
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    // Discard messages for removed subscriptions and strip the root
+    // Messages published with our origin are our own reflected back
+    fn received(&self, mut message: Message) -> Option<Message> {
+        if !self.subscriptions.contains_key(&message.sid) {
+            return None;
+        }
+        let origin = &self.options.origin;
+        let marked = match message.headers.get(ORIGIN_HEADER) {
+            Some(values) => values.contains(origin),
+            None => false,
+        };
+        if !origin.is_empty() && marked {
+            return None;
+        }
+        if !self.root.is_empty() {
+            message.subject =
+                message.subject[self.root.len() + 1..].to_string();
+            message.root = self.root.to_string();
+        }
+        Some(message)
+    }
 
-                    let source: String = detail[1].into();
+    /// ## Read Timeout
+    ///
+    /// Seconds to wait for a message before `next_event` returns
+    /// `Error::Timeout`, like to check for a shutdown request.
+    /// The connection is checked with a PING after 30 idle seconds,
+    /// and reconnected when the PONG has not arrived 30 seconds later.
+    pub fn set_timeout(&mut self, timeout: u64) {
+        self.socket.set_timeout(timeout);
+    }
 
-                    let subject = if self.root.is_empty() {
-                        source
-                    } else {
-                        source[self.root.len() + 1..].to_string()
-                    };
+    /// ## Server Information
+    ///
+    /// The INFO of the connected server, kept up to date by INFO updates.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Options, SubscribeClient};
+    ///
+    /// let options = Options::default();
+    /// let nats = SubscribeClient::new("0.0.0.0:4222", "", "demo", &options)
+    ///     .expect("NATS Subscribe Client");
+    ///
+    /// println!("{}", nats.server_info().server_id);
+    /// ```
+    pub fn server_info(&self) -> &ServerInfo {
+        &self.info
+    }
+
+    /// ## Drain and Close
+    ///
+    /// Unsubscribe from everything and close the connection once the
+    /// server confirms it has no more messages for us.
+    /// Returns the messages that were already on their way.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Options, SubscribeClient};
+    ///
+    /// let options = Options::default();
+    /// let nats = SubscribeClient::new("0.0.0.0:4222", "", "demo", &options)
+    ///     .expect("NATS Subscribe Client");
+    ///
+    /// for message in nats.drain().expect("Drained") {
+    ///     println!("{:?}", message.data());
+    /// }
+    /// ```
+    pub fn drain(mut self) -> Result<Vec<Message>, Error> {
+        // The PONG comes after every message sent before the UNSUB
+        let mut commands = String::new();
+        for sid in self.subscriptions.keys() {
+            commands.push_str(&format!("UNSUB {}\r\n", sid));
+        }
+        commands.push_str("PING\r\n");
+        if self.socket.write(commands).is_err() {
+            return Err(Error::Unsubscribe);
+        }
 
-                    return Ok(Message {
-                        root: self.root.to_string(),
-                        subject,
-                        my_id: detail[2].into(),
-                        sender_id: detail[3].into(),
-                        data: message.trim().into(),
-                    });
+        let mut messages = Vec::new();
+        loop {
+            let data = match self.socket.readln() {
+                Ok(data) => data,
+                Err(socket::Error::Timeout) => return Err(Error::Timeout),
+                Err(_) => return Err(Error::Unsubscribe),
+            };
+            let detail: Vec<_> = data.split_whitespace().collect();
+            match detail.first() {
+                Some(&"PONG") => break,
+                Some(&"PING") => {
+                    if self.socket.write("PONG\r\n").is_err() {
+                        return Err(Error::Unsubscribe);
+                    }
+                }
+                Some(&"MSG") | Some(&"HMSG") => {
+                    let message = match read_msg(&mut self.socket, &detail) {
+                        Some(message) => message,
+                        None => return Err(Error::Unsubscribe),
+                    };
+                    if let Some(message) = self.received(message) {
+                        messages.push(message);
+                    }
                 }
                 _ => continue,
             }
         }
+
+        self.socket.disconnect();
+        Ok(messages)
     }
 
     #[cfg(test)]
@@ -224,9 +957,11 @@ impl Drop for SubscribeClient {
 /// This client lib offers publish support to NATS.
 ///
 /// ```no_run
-/// use nats_bridge::nats::PublishClient;
+/// use nats_bridge::nats::{Options, PublishClient};
 ///
-/// let mut nats = PublishClient::new("0.0.0.0:4222", "").expect("NATS PUB");
+/// let options = Options::default();
+/// let mut nats = PublishClient::new("0.0.0.0:4222", "", &options)
+///     .expect("NATS PUB");
 ///
 /// loop {
 ///     let result = nats.publish("hello", "subject");
@@ -236,37 +971,79 @@ impl Drop for SubscribeClient {
 ///
 // =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
 impl PublishClient {
-    pub fn new(host: &str, root: &str) -> Result<Self, Error> {
+    pub fn new(
+        host: &str,
+        root: &str,
+        options: &Options,
+    ) -> Result<Self, Error> {
         let mut socket = Socket::new(host, "NATS Publisher", 5);
-        let _infoln = match socket.readln() {
-            Ok(data) => data,
-            Err(_) => return Err(Error::Initialize),
-        };
+        let info = handshake(&mut socket, options)?;
 
         Ok(Self {
             socket,
+            options: options.clone(),
             root: root.into(),
+            sid: 0,
+            info,
+            buffer: Vec::new(),
+            buffered: 0,
+            last_write: Instant::now(),
+            unconfirmed: false,
+            lost: false,
         })
     }
 
     /// ## Send NATS Messages
     ///
     /// Easy way to send messages to any NATS subject.
+    /// The payload may be any bytes, it is sent with its exact length.
+    /// With a `flush_interval` the message may stay in the buffer until
+    /// `flush()` is called.
     ///
     /// ```no_run
-    /// use nats_bridge::nats::PublishClient;
+    /// use nats_bridge::nats::{Options, PublishClient};
     ///
     /// let subject = "demo";
     /// let root = "root";
-    /// let mut nats = PublishClient::new("0.0.0.0:4222", root)
+    /// let options = Options::default();
+    /// let mut nats = PublishClient::new("0.0.0.0:4222", root, &options)
     ///     .expect("NATS Publish Client");
     ///
     /// nats.publish(subject, "Hello").expect("publish sent");
+    /// nats.publish(subject, [0u8, 159, 146, 150]).expect("publish sent");
     /// ```
     pub fn publish(
         &mut self,
         subject: impl AsRef<str>,
-        data: impl AsRef<str>,
+        data: impl AsRef<[u8]>,
+    ) -> Result<(), Error> {
+        self.publish_with_headers(subject, &Headers::new(), data)
+    }
+
+    /// ## Send NATS Messages with Headers
+    ///
+    /// Headers such as tracing ids and content types travel with the
+    /// message using `HPUB`. The server must support headers.
+    /// The `ORIGIN_HEADER` is added when `Options::origin` is set, as it is
+    /// to replies and requests.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Headers, Options, PublishClient};
+    ///
+    /// let options = Options::default();
+    /// let mut nats = PublishClient::new("0.0.0.0:4222", "root", &options)
+    ///     .expect("NATS Publish Client");
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.insert("Content-Type".into(), vec!["application/json".into()]);
+    /// nats.publish_with_headers("demo", &headers, "{}")
+    ///     .expect("publish sent");
+    /// ```
+    pub fn publish_with_headers(
+        &mut self,
+        subject: impl AsRef<str>,
+        headers: &Headers,
+        data: impl AsRef<[u8]>,
     ) -> Result<(), Error> {
         let subject = if self.root.is_empty() {
             subject.as_ref().to_string()
@@ -278,21 +1055,517 @@ impl PublishClient {
             )
         };
 
-        let pubcmd = format!(
-            "PUB {subject} {length}\r\n{data}\r\n",
-            subject = subject,
-            length = data.as_ref().len(),
-            data = data.as_ref(),
-        );
-        match self.socket.write(pubcmd) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(Error::Publish),
-        }
+        self.send_pub(&subject, "", headers, data.as_ref())
     }
 
-    #[cfg(test)]
-    pub fn ping(&mut self) -> Result<String, Error> {
-        let _size = match self.socket.write("PING\r\n") {
+    /// ## Reply to NATS Requests
+    ///
+    /// Send a response to the `reply_to` subject of a received message.
+    /// Reply subjects are used as is, without the root.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Options, PublishClient};
+    ///
+    /// let options = Options::default();
+    /// let mut nats = PublishClient::new("0.0.0.0:4222", "root", &options)
+    ///     .expect("NATS Publish Client");
+    ///
+    /// nats.reply("_INBOX.2bOSjvkI7uBN5gBlCqHfJp", "Hello")
+    ///     .expect("reply sent");
+    /// ```
+    pub fn reply(
+        &mut self,
+        reply_to: impl AsRef<str>,
+        data: impl AsRef<[u8]>,
+    ) -> Result<(), Error> {
+        self.send_pub(reply_to.as_ref(), "", &Headers::new(), data.as_ref())
+    }
+
+    /// ## Reply to NATS Requests with Headers
+    ///
+    /// Same as `reply` with headers sent using `HPUB`.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Headers, Options, PublishClient};
+    ///
+    /// let options = Options::default();
+    /// let mut nats = PublishClient::new("0.0.0.0:4222", "root", &options)
+    ///     .expect("NATS Publish Client");
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.insert("Trace-Id".into(), vec!["4bf92f3577b34da6".into()]);
+    /// nats.reply_with_headers("_INBOX.2bOSjvkI7uBN5gBlCqHfJp", &headers, "Hi")
+    ///     .expect("reply sent");
+    /// ```
+    pub fn reply_with_headers(
+        &mut self,
+        reply_to: impl AsRef<str>,
+        headers: &Headers,
+        data: impl AsRef<[u8]>,
+    ) -> Result<(), Error> {
+        self.send_pub(reply_to.as_ref(), "", headers, data.as_ref())
+    }
+
+    /// ## Request a NATS Service
+    ///
+    /// Publish with a unique reply subject and wait for the first response.
+    /// Returns `Error::Timeout` when no response arrives in time.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Options, PublishClient};
+    /// use std::time::Duration;
+    ///
+    /// let options = Options::default();
+    /// let mut nats = PublishClient::new("0.0.0.0:4222", "root", &options)
+    ///     .expect("NATS Publish Client");
+    ///
+    /// let timeout = Duration::from_secs(2);
+    /// let response = nats.request("time", "now", timeout).expect("response");
+    /// println!("{:?}", response.data());
+    /// ```
+    pub fn request(
+        &mut self,
+        subject: impl AsRef<str>,
+        data: impl AsRef<[u8]>,
+        timeout: Duration,
+    ) -> Result<Message, Error> {
+        self.request_with_headers(subject, &Headers::new(), data, timeout)
+    }
+
+    /// ## Request a NATS Service with Headers
+    ///
+    /// Same as `request` with headers sent using `HPUB`.
+    /// Returns `Error::NoResponders` when nothing listens on the subject.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Headers, Options, PublishClient};
+    /// use std::time::Duration;
+    ///
+    /// let options = Options::default();
+    /// let mut nats = PublishClient::new("0.0.0.0:4222", "root", &options)
+    ///     .expect("NATS Publish Client");
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.insert("Trace-Id".into(), vec!["4bf92f3577b34da6".into()]);
+    /// let timeout = Duration::from_secs(2);
+    /// let response = nats
+    ///     .request_with_headers("time", &headers, "now", timeout)
+    ///     .expect("response");
+    /// ```
+    pub fn request_with_headers(
+        &mut self,
+        subject: impl AsRef<str>,
+        headers: &Headers,
+        data: impl AsRef<[u8]>,
+        timeout: Duration,
+    ) -> Result<Message, Error> {
+        let deadline = Instant::now() + timeout;
+        let subject = if self.root.is_empty() {
+            subject.as_ref().to_string()
+        } else {
+            format!(
+                "{root}.{subject}",
+                subject = subject.as_ref(),
+                root = self.root
+            )
+        };
+
+        // Subscribe to the inbox for a single response before publishing
+        self.sid += 1;
+        let sid = self.sid.to_string();
+        let inbox = inbox();
+        let sub = format!(
+            "SUB {inbox} {sid}\r\nUNSUB {sid} 1\r\n",
+            inbox = inbox,
+            sid = sid,
+        );
+        self.connection()?;
+        self.write_buffer()?;
+        if self.socket.write(sub).is_err() {
+            return Err(Error::Request);
+        }
+        self.send_pub(&subject, &inbox, headers, data.as_ref())?;
+        self.write_buffer()?;
+
+        let response = self.wait_for(&sid, deadline);
+        self.socket.reset_read_timeout();
+
+        // Stop waiting for a late response
+        if response.is_err() {
+            let _ = self.socket.write(format!("UNSUB {}\r\n", sid));
+        }
+        response
+    }
+
+    /// ## Publish to JetStream
+    ///
+    /// Publish to a subject captured by a JetStream stream and wait for the
+    /// stream to acknowledge that the message is stored.
+    /// Set the `Nats-Msg-Id` header so a retried publish is stored once,
+    /// and `Nats-Expected-Stream` to make sure the right stream stores it.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Headers, Options, PublishClient};
+    /// use std::time::Duration;
+    ///
+    /// let options = Options::default();
+    /// let mut nats = PublishClient::new("0.0.0.0:4222", "root", &options)
+    ///     .expect("NATS Publish Client");
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.insert("Nats-Msg-Id".into(), vec!["16195023423187265".into()]);
+    /// let timeout = Duration::from_secs(2);
+    /// let ack = nats
+    ///     .jetstream_publish("orders", &headers, "{}", timeout)
+    ///     .expect("stored");
+    /// println!("{} {} {}", ack.stream, ack.sequence, ack.duplicate);
+    /// ```
+    pub fn jetstream_publish(
+        &mut self,
+        subject: impl AsRef<str>,
+        headers: &Headers,
+        data: impl AsRef<[u8]>,
+        timeout: Duration,
+    ) -> Result<PubAck, Error> {
+        let response =
+            self.request_with_headers(subject, headers, data, timeout)?;
+        let ack = match response.data().map(json::parse) {
+            Some(Ok(ack)) => ack,
+            _ => return Err(Error::JetStream("Invalid PubAck".into())),
+        };
+
+        // {"error":{"code":503,"err_code":10077,"description":"..."}}
+        if !ack["error"].is_null() {
+            let description = ack["error"]["description"].to_string();
+            return Err(Error::JetStream(description));
+        }
+        match (ack["stream"].as_str(), ack["seq"].as_u64()) {
+            (Some(stream), Some(sequence)) => Ok(PubAck {
+                stream: stream.into(),
+                sequence,
+                duplicate: ack["duplicate"].as_bool().unwrap_or(false),
+            }),
+            _ => Err(Error::JetStream("Invalid PubAck".into())),
+        }
+    }
+
+    // Read until the message for `sid` arrives or the deadline passes
+    fn wait_for(
+        &mut self,
+        sid: &str,
+        deadline: Instant,
+    ) -> Result<Message, Error> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout);
+            }
+            self.socket.set_read_timeout(deadline - now);
+
+            let data = match self.socket.readln() {
+                Ok(data) => data,
+                Err(socket::Error::Timeout) => return Err(Error::Timeout),
+                Err(_) => return Err(Error::Request),
+            };
+
+            let detail: Vec<_> = data.split_whitespace().collect();
+            match detail.first() {
+                Some(&"PING") => {
+                    if self.socket.write("PONG\r\n").is_err() {
+                        return Err(Error::Request);
+                    }
+                }
+                Some(&"-ERR") => return Err(server_error(&data)),
+                Some(&"INFO") => {
+                    if let Some(info) = info_update(&data) {
+                        self.socket.add_servers(&info.connect_urls);
+                        self.info = info;
+                    }
+                }
+                Some(&"MSG") | Some(&"HMSG") => {
+                    let message = match read_msg(&mut self.socket, &detail) {
+                        Some(message) => message,
+                        None => return Err(Error::Request),
+                    };
+                    if message.sid != sid {
+                        continue;
+                    }
+                    if message.status == "503" {
+                        return Err(Error::NoResponders);
+                    }
+                    return Ok(message);
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// ## Server Information
+    ///
+    /// The INFO of the connected server.
+    /// Check `max_payload` before publishing large messages.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Options, PublishClient};
+    ///
+    /// let options = Options::default();
+    /// let nats = PublishClient::new("0.0.0.0:4222", "", &options)
+    ///     .expect("NATS Publish Client");
+    ///
+    /// println!("{}", nats.server_info().max_payload);
+    /// ```
+    pub fn server_info(&self) -> &ServerInfo {
+        &self.info
+    }
+
+    /// ## Drain and Close
+    ///
+    /// Close the connection once the server has received every message
+    /// published so far.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Options, PublishClient};
+    ///
+    /// let options = Options::default();
+    /// let mut nats = PublishClient::new("0.0.0.0:4222", "", &options)
+    ///     .expect("NATS Publish Client");
+    ///
+    /// nats.publish("demo", "Goodbye").expect("publish sent");
+    /// nats.drain().expect("Drained");
+    /// ```
+    pub fn drain(mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.socket.disconnect();
+        Ok(())
+    }
+
+    /// ## Flush Publishes
+    ///
+    /// Write the buffered messages and wait until the server has received
+    /// them. The server handles commands in order, so the PONG confirms
+    /// everything written before the PING.
+    /// Returns at once when nothing was published since the last flush.
+    /// Returns `Error::Publish` when the connection was lost before
+    /// messages written to it were confirmed, they may not have arrived.
+    ///
+    /// ```no_run
+    /// use nats_bridge::nats::{Options, PublishClient};
+    /// use std::time::Duration;
+    ///
+    /// let options = Options {
+    ///     flush_interval: Duration::from_millis(100),
+    ///     ..Options::default()
+    /// };
+    /// let mut nats = PublishClient::new("0.0.0.0:4222", "", &options)
+    ///     .expect("NATS Publish Client");
+    ///
+    /// for count in 0..1000 {
+    ///     nats.publish("demo", count.to_string()).expect("publish sent");
+    /// }
+    /// nats.flush().expect("Flushed");
+    /// ```
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.connection()?;
+        self.write_buffer()?;
+        if self.unconfirmed {
+            self.confirm()?;
+        }
+        if self.lost {
+            self.lost = false;
+            return Err(Error::Publish);
+        }
+        Ok(())
+    }
+
+    // PING and wait for the PONG
+    fn confirm(&mut self) -> Result<(), Error> {
+        if self.socket.write("PING\r\n").is_err() {
+            return Err(Error::Ping);
+        }
+        loop {
+            let data = match self.socket.readln() {
+                Ok(data) => data,
+                Err(socket::Error::Timeout) => return Err(Error::Timeout),
+                Err(_) => return Err(Error::Ping),
+            };
+            let detail: Vec<_> = data.split_whitespace().collect();
+            match detail.first() {
+                Some(&"PONG") => {
+                    self.unconfirmed = false;
+                    return Ok(());
+                }
+                Some(&"PING") => {
+                    if self.socket.write("PONG\r\n").is_err() {
+                        return Err(Error::Ping);
+                    }
+                }
+                Some(&"-ERR") => return Err(server_error(&data)),
+                Some(&"INFO") => {
+                    if let Some(info) = info_update(&data) {
+                        self.socket.add_servers(&info.connect_urls);
+                        self.info = info;
+                    }
+                }
+                Some(&"MSG") | Some(&"HMSG") => {
+                    if read_msg(&mut self.socket, &detail).is_none() {
+                        return Err(Error::Ping);
+                    }
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    // Repeat the handshake after losing the connection
+    // Writes to the old connection that were not confirmed may be lost
+    fn connection(&mut self) -> Result<(), Error> {
+        if self.socket.is_connected() {
+            return Ok(());
+        }
+        self.socket.reconnect();
+        self.info = handshake(&mut self.socket, &self.options)?;
+        if self.unconfirmed {
+            self.unconfirmed = false;
+            self.lost = true;
+        }
+        Ok(())
+    }
+
+    fn send_pub(
+        &mut self,
+        subject: &str,
+        reply_to: &str,
+        headers: &Headers,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.connection()?;
+
+        // Mark the message so our own subscribers can drop it
+        // JetStream API calls and acks are not messages
+        let origin = &self.options.origin;
+        let marked;
+        let headers = if origin.is_empty()
+            || !self.info.headers
+            || subject.starts_with("$JS.")
+        {
+            headers
+        } else {
+            let mut copy = headers.clone();
+            copy.insert(ORIGIN_HEADER.into(), vec![origin.clone()]);
+            marked = copy;
+            &marked
+        };
+
+        // Wildcards and whitespace would break the protocol line
+        if !subject::is_valid(subject) {
+            return Err(Error::InvalidSubject(subject.into()));
+        }
+        if !reply_to.is_empty() && !subject::is_valid(reply_to) {
+            return Err(Error::InvalidSubject(reply_to.into()));
+        }
+
+        // Line breaks would end the header block early
+        let invalid = |text: &String| text.contains(&['\r', '\n'][..]);
+        if headers
+            .iter()
+            .any(|(name, values)| invalid(name) || values.iter().any(invalid))
+        {
+            return Err(Error::Publish);
+        }
+
+        // Messages without headers use plain PUB
+        let (command, block) = if headers.is_empty() {
+            ("PUB", String::new())
+        } else if self.info.headers {
+            ("HPUB", header_block(headers))
+        } else {
+            self.socket.log("Server does not support headers");
+            return Err(Error::Publish);
+        };
+
+        // The server closes the connection on oversized messages
+        if block.len() + data.len() > self.info.max_payload {
+            return Err(Error::PayloadTooLarge);
+        }
+        let length = if headers.is_empty() {
+            data.len().to_string()
+        } else {
+            format!("{} {}", block.len(), block.len() + data.len())
+        };
+        let mut pubcmd = if reply_to.is_empty() {
+            format!(
+                "{command} {subject} {length}\r\n",
+                command = command,
+                subject = subject,
+                length = length,
+            )
+        } else {
+            format!(
+                "{command} {subject} {reply_to} {length}\r\n",
+                command = command,
+                subject = subject,
+                reply_to = reply_to,
+                length = length,
+            )
+        }
+        .into_bytes();
+        if pubcmd.len() > MAX_CONTROL_LINE {
+            return Err(Error::ControlLineTooLong);
+        }
+        pubcmd.extend_from_slice(block.as_bytes());
+        pubcmd.extend_from_slice(data);
+        pubcmd.extend_from_slice(b"\r\n");
+
+        // A full buffer that cannot be written takes no more messages
+        if self.buffer.len() >= MAX_BUFFER {
+            self.write_buffer()?;
+        }
+        self.buffer.extend_from_slice(&pubcmd);
+        self.buffered += 1;
+
+        // Coalesce frames until the interval passes or the buffer fills
+        if self.last_write.elapsed() >= self.options.flush_interval
+            || self.buffer.len() >= MAX_BUFFER
+        {
+            self.write_buffer()
+        } else {
+            Ok(())
+        }
+    }
+
+    // One write and one log line for all the buffered frames
+    // The frames stay buffered until they are written
+    fn write_buffer(&mut self) -> Result<(), Error> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.connection()?;
+
+        let written = if self.buffered == 1 {
+            self.socket.write(&self.buffer)
+        } else {
+            self.socket.log(&format!(
+                "PUB {} messages, {} bytes",
+                self.buffered,
+                self.buffer.len()
+            ));
+            self.socket.send(&self.buffer)
+        };
+        match written {
+            Ok(_) => {
+                self.buffer.clear();
+                self.buffered = 0;
+                self.last_write = Instant::now();
+                self.unconfirmed = true;
+                Ok(())
+            }
+            Err(_) => Err(Error::Publish),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn ping(&mut self) -> Result<String, Error> {
+        let _size = match self.socket.write("PING\r\n") {
             Ok(size) => size,
             Err(_error) => return Err(Error::Ping),
         };
@@ -302,146 +1575,2054 @@ impl PublishClient {
         }
     }
 
-    #[cfg(test)]
-    pub fn exit(&mut self) -> Result<(), Error> {
-        match self.socket.write("EXIT\r\n") {
-            Ok(_size) => Ok(()),
-            Err(_error) => Err(Error::Exit),
+    #[cfg(test)]
+    pub fn exit(&mut self) -> Result<(), Error> {
+        match self.socket.write("EXIT\r\n") {
+            Ok(_size) => Ok(()),
+            Err(_error) => Err(Error::Exit),
+        }
+    }
+}
+
+// Never reconnect from drop, the server may be gone for good
+impl Drop for PublishClient {
+    fn drop(&mut self) {
+        if self.socket.is_connected() {
+            let _ = self.write_buffer();
+        }
+        self.socket.disconnect();
+    }
+}
+
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+/// # JetStream Consumer
+///
+/// Pull messages from a durable JetStream consumer, one at a time.
+/// The consumer remembers what was acknowledged, so messages published
+/// while the client was away are delivered when it returns.
+/// Messages that are not acknowledged in time are delivered again.
+///
+/// ```no_run
+/// use nats_bridge::nats::{Consumer, Options};
+/// use std::time::Duration;
+///
+/// let options = Options::default();
+/// let subjects = ["demo"]; // subjects.demo
+/// let mut consumer = Consumer::new(
+///     "0.0.0.0:4222",
+///     "subjects",
+///     "ORDERS",
+///     "nats-bridge",
+///     &subjects,
+///     &options,
+/// )
+/// .expect("JetStream Consumer");
+///
+/// let message = consumer
+///     .next_message(Duration::from_secs(5))
+///     .expect("Received Message");
+/// println!("{} -> {:?}", message.subject, message.data());
+/// consumer.ack(&message).expect("Acknowledged");
+/// ```
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+pub struct Consumer {
+    client: PublishClient,
+    root: String,
+    stream: String,
+    durable: String,
+}
+
+impl Consumer {
+    pub fn new(
+        host: &str,
+        root: &str,
+        stream: &str,
+        durable: &str,
+        subjects: &[&str],
+        options: &Options,
+    ) -> Result<Self, Error> {
+        let mut consumer =
+            Self::connect(host, root, stream, durable, options)?;
+
+        // Create the durable consumer, or keep the existing one
+        let filters: Vec<String> = subjects
+            .iter()
+            .map(|subject| consumer.rooted(subject))
+            .collect();
+        if let Some(filter) = filters
+            .iter()
+            .find(|filter| !subject::is_valid_pattern(filter))
+        {
+            return Err(Error::InvalidSubject(filter.clone()));
+        }
+        let mut config = json::object! {
+            "durable_name" => durable,
+            "deliver_policy" => "all",
+            "ack_policy" => "explicit",
+        };
+        match filters.as_slice() {
+            [] => {}
+            [filter] => config["filter_subject"] = filter.as_str().into(),
+            _ => config["filter_subjects"] = filters.into(),
+        }
+        let request = json::object! {
+            "stream_name" => stream,
+            "config" => config,
+        };
+        let api =
+            format!("$JS.API.CONSUMER.DURABLE.CREATE.{}.{}", stream, durable);
+        consumer.create(&api, &request)?;
+        Ok(consumer)
+    }
+
+    fn connect(
+        host: &str,
+        root: &str,
+        stream: &str,
+        durable: &str,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        // JetStream API subjects are never under the root
+        let client = PublishClient::new(host, "", options)?;
+        Ok(Self {
+            client,
+            root: root.into(),
+            stream: stream.into(),
+            durable: durable.into(),
+        })
+    }
+
+    // JetStream API request for a new consumer
+    fn create(
+        &mut self,
+        api: &str,
+        request: &JsonValue,
+    ) -> Result<JsonValue, Error> {
+        let response = self.client.request(
+            api,
+            json::stringify(request.clone()),
+            Duration::from_secs(5),
+        )?;
+        let response = match response.data().map(json::parse) {
+            Some(Ok(response)) => response,
+            _ => return Err(Error::JetStream("Invalid Response".into())),
+        };
+        if !response["error"].is_null() {
+            let description = response["error"]["description"].to_string();
+            return Err(Error::JetStream(description));
+        }
+        Ok(response)
+    }
+
+    /// ## Pull the Next Message
+    ///
+    /// Wait up to `wait` for a message, `Error::Timeout` when there is none.
+    /// The message `reply_to` is the subject used to acknowledge it.
+    pub fn next_message(&mut self, wait: Duration) -> Result<Message, Error> {
+        let api = format!(
+            "$JS.API.CONSUMER.MSG.NEXT.{}.{}",
+            self.stream, self.durable
+        );
+        let request = json::object! {
+            "batch" => 1,
+            "expires" => wait.as_nanos() as u64,
+        };
+
+        // The server ends the pull at `expires`, allow time for the answer
+        let timeout = wait + Duration::from_secs(1);
+        let mut message =
+            self.client
+                .request(api, json::stringify(request), timeout)?;
+
+        // 404 No Messages, 408 Request Timeout
+        match message.status.as_str() {
+            "" => {}
+            "404" | "408" => return Err(Error::Timeout),
+            status => {
+                let description = if message.description.is_empty() {
+                    status.to_string()
+                } else {
+                    message.description.to_string()
+                };
+                return Err(Error::JetStream(description));
+            }
+        }
+
+        let prefix = format!("{}.", self.root);
+        if !self.root.is_empty() && message.subject.starts_with(&prefix) {
+            message.subject = message.subject[prefix.len()..].to_string();
+            message.root = self.root.to_string();
+        }
+        Ok(message)
+    }
+
+    /// ## Acknowledge a Message
+    ///
+    /// Tell the consumer the message was handled so it is not delivered
+    /// again. Only acknowledge once the message has reached its destination.
+    pub fn ack(&mut self, message: &Message) -> Result<(), Error> {
+        self.client.reply(&message.reply_to, "+ACK")
+    }
+
+    /// ## Terminate a Message
+    ///
+    /// Tell the consumer to stop delivering a message that can never
+    /// reach its destination, without acknowledging it was handled.
+    pub fn term(&mut self, message: &Message) -> Result<(), Error> {
+        self.client.reply(&message.reply_to, "+TERM")
+    }
+
+    fn rooted(&self, subject: &str) -> String {
+        if self.root.is_empty() {
+            subject.to_string()
+        } else {
+            format!("{}.{}", self.root, subject)
+        }
+    }
+}
+
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+/// # Key-Value Watcher
+///
+/// Follow the changes to a NATS Key-Value bucket.
+/// The latest value of every key is delivered first, the snapshot is
+/// complete when an entry has no `pending` entries after it.
+/// Deleted and purged keys are delivered with an empty value.
+///
+/// ```no_run
+/// use nats_bridge::nats::{Operation, Options, Watcher};
+/// use std::time::Duration;
+///
+/// let options = Options::default();
+/// let mut watcher = Watcher::new("0.0.0.0:4222", "config", &options)
+///     .expect("Key-Value Watcher");
+///
+/// let entry = watcher
+///     .next_entry(Duration::from_secs(5))
+///     .expect("Key-Value Entry");
+/// match entry.operation {
+///     Operation::Put => println!("{} = {:?}", entry.key, entry.value),
+///     _ => println!("{} deleted", entry.key),
+/// }
+/// ```
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+pub struct Watcher {
+    consumer: Consumer,
+    bucket: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Operation {
+    Put,
+    Delete,
+    Purge,
+}
+
+impl Operation {
+    /// The `KV-Operation` header value
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Put => "PUT",
+            Operation::Delete => "DEL",
+            Operation::Purge => "PURGE",
+        }
+    }
+}
+
+pub struct Entry {
+    pub bucket: String,
+    pub key: String,
+    pub value: Vec<u8>,
+    pub revision: u64,
+    pub pending: u64,
+    pub operation: Operation,
+}
+
+impl Watcher {
+    pub fn new(
+        host: &str,
+        bucket: &str,
+        options: &Options,
+    ) -> Result<Self, Error> {
+        let stream = format!("KV_{}", bucket);
+        let mut consumer = Consumer::connect(host, "", &stream, "", options)?;
+
+        // Ephemeral consumer, removed by the server once we stop pulling
+        let config = json::object! {
+            "deliver_policy" => "last_per_subject",
+            "ack_policy" => "explicit",
+            "filter_subject" => format!("$KV.{}.>", bucket),
+            "inactive_threshold" => Duration::from_secs(60).as_nanos() as u64,
+        };
+        let request = json::object! {
+            "stream_name" => stream.as_str(),
+            "config" => config,
+        };
+        let api = format!("$JS.API.CONSUMER.CREATE.{}", stream);
+        let response = consumer.create(&api, &request)?;
+        consumer.durable = match response["name"].as_str() {
+            Some(name) => name.into(),
+            None => return Err(Error::JetStream("Invalid Response".into())),
+        };
+
+        Ok(Self {
+            consumer,
+            bucket: bucket.into(),
+        })
+    }
+
+    /// ## Next Key-Value Change
+    ///
+    /// Wait up to `wait` for a change, `Error::Timeout` when there is none.
+    pub fn next_entry(&mut self, wait: Duration) -> Result<Entry, Error> {
+        let message = self.consumer.next_message(wait)?;
+        self.consumer.ack(&message)?;
+
+        let (revision, pending) = ack_metadata(&message.reply_to);
+        let prefix = format!("$KV.{}.", self.bucket);
+        let key = message.subject.trim_start_matches(&prefix).to_string();
+        let operation = match message
+            .headers
+            .get("KV-Operation")
+            .and_then(|values| values.first())
+        {
+            Some(operation) if operation == "DEL" => Operation::Delete,
+            Some(operation) if operation == "PURGE" => Operation::Purge,
+            _ => Operation::Put,
+        };
+
+        Ok(Entry {
+            bucket: self.bucket.to_string(),
+            key,
+            value: message.payload,
+            revision,
+            pending,
+            operation,
+        })
+    }
+}
+
+// Stream sequence and pending count from a JetStream reply subject
+// $JS.ACK.<stream>.<consumer>.<delivered>.<stream seq>.<consumer seq>.
+//     <timestamp>.<pending>
+// Newer servers add a domain and account hash after $JS.ACK and a token
+// at the end.
+fn ack_metadata(reply_to: &str) -> (u64, u64) {
+    let tokens: Vec<&str> = reply_to.split('.').collect();
+    let offset = if tokens.len() >= 12 { 2 } else { 0 };
+    let number = |index: usize| {
+        tokens
+            .get(index + offset)
+            .and_then(|token| token.parse().ok())
+            .unwrap_or(0)
+    };
+    (number(5), number(8))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::ssl::{SslAcceptor, SslMethod, SslVerifyMode};
+    use openssl::x509::extension::{
+        BasicConstraints, SubjectAlternativeName,
+    };
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    struct NATSMock {
+        listener: TcpListener,
+    }
+
+    const INFO: &str = "INFO {\"server_id\":\"asbLGfs3r7pgZwucUxYnPn\",\"version\":\"1.4.1\",\"proto\":1,\"git_commit\":\"3e64f0b\",\"go\":\"go1.11.5\",\"host\":\"0.0.0.0\",\"port\":4222,\"headers\":true,\"max_payload\":1048576,\"client_id\":9999}\r\n";
+    const NONCE_INFO: &str = "INFO {\"server_id\":\"asbLGfs3r7pgZwucUxYnPn\",\"version\":\"2.10.0\",\"proto\":1,\"host\":\"0.0.0.0\",\"port\":4222,\"auth_required\":true,\"nonce\":\"PXoWU7zWAMt75FY\",\"headers\":true,\"max_payload\":1048576,\"client_id\":9999}\r\n";
+    const SIGNATURE: &str = "RkyUfhj0hZV4SGosA4w8Z2jQjAdZCALBJzhNdhfW6581WHG7tdqsG6pGlr5MATr_YsnnyJOjpr9o2S3IT6iUBA";
+    const CREDS: &str = "-----BEGIN NATS USER JWT-----\neyJ0eXAiOiJKV1QiLCJhbGciOiJlZDI1NTE5LW5rZXkifQ\n------END NATS USER JWT------\n\n-----BEGIN USER NKEY SEED-----\nSUAACAQDAQCQMBYIBEFAWDANBYHRAEISCMKBKFQXDAMRUGY4DUPB6IC5CQ\n------END USER NKEY SEED------\n";
+    const TLS_INFO: &str = "INFO {\"server_id\":\"asbLGfs3r7pgZwucUxYnPn\",\"version\":\"1.4.1\",\"proto\":1,\"host\":\"0.0.0.0\",\"port\":4222,\"tls_required\":true,\"tls_verify\":true,\"headers\":true,\"max_payload\":1048576,\"client_id\":9999}\r\n";
+
+    impl NATSMock {
+        fn new(host: &str) -> std::io::Result<Self> {
+            let listener = TcpListener::bind(host)?;
+            Ok(Self { listener })
+        }
+
+        // Lines received from the client, for tests to inspect
+        fn process(&self) -> Vec<String> {
+            match self.listener.accept() {
+                Ok((mut socket, _addr)) => {
+                    socket
+                        .write_all(INFO.as_bytes())
+                        .expect("Could not send info");
+                    Self::serve(socket)
+                }
+                Err(e) => {
+                    eprintln!("couldn't get client: {:?}", e);
+                    Vec::new()
+                }
+            }
+        }
+
+        // Operator mode server that requires a signed nonce
+        fn process_nonce(&self) {
+            match self.listener.accept() {
+                Ok((mut socket, _addr)) => {
+                    socket
+                        .write_all(NONCE_INFO.as_bytes())
+                        .expect("Could not send info");
+                    Self::serve(socket);
+                }
+                Err(e) => eprintln!("couldn't get client: {:?}", e),
+            }
+        }
+
+        fn process_tls(&self, acceptor: &SslAcceptor) {
+            match self.listener.accept() {
+                Ok((mut socket, _addr)) => {
+                    socket
+                        .write_all(TLS_INFO.as_bytes())
+                        .expect("Could not send info");
+                    match acceptor.accept(socket) {
+                        Ok(stream) => {
+                            Self::serve(stream);
+                        }
+                        Err(e) => eprintln!("TLS handshake failed: {:?}", e),
+                    }
+                }
+                Err(e) => eprintln!("couldn't get client: {:?}", e),
+            }
+        }
+
+        fn serve(socket: impl Read + Write) -> Vec<String> {
+            let mut reader = BufReader::new(socket);
+            let mut line = String::new();
+            let mut lines = Vec::new();
+            let mut inbox_sid = String::new();
+            let mut sequence = 0;
+            let mut acked = false;
+            let mut kv_step = 0;
+
+            loop {
+                line.clear();
+                let size =
+                    reader.read_line(&mut line).expect("Unable to read line");
+                if size == 0 {
+                    eprintln!("Socket disconnected while reading");
+                    break;
+                }
+                lines.push(line.clone());
+
+                let socket = reader.get_mut();
+                match line.as_ref() {
+                    inbox if inbox.starts_with("SUB _INBOX.") => {
+                        let detail: Vec<_> =
+                            inbox.split_whitespace().collect();
+                        inbox_sid = detail[2].to_string();
+                    }
+                    "EXIT\r\n" => break,
+                    connect if connect.starts_with("CONNECT ") => {
+                        let signed = connect.contains(SIGNATURE);
+                        let jwt = connect.contains("\"jwt\":");
+                        if connect.contains("\"auth_token\":\"bad\"")
+                            || (jwt && !signed)
+                        {
+                            socket
+                                .write_all(
+                                    b"-ERR 'Authorization Violation'\r\n",
+                                )
+                                .expect("Unable to write");
+                            break;
+                        }
+                    }
+                    "PING\r\n" => {
+                        socket
+                            .write_all(b"PONG\r\n")
+                            .expect("Unable to write");
+                    }
+                    sub if sub.starts_with("SUB ") => {
+                        let detail: Vec<_> = sub.split_whitespace().collect();
+                        let (subject, sid) =
+                            (detail[1], detail[detail.len() - 1]);
+
+                        // Binary payload with line breaks and spaces first
+                        if subject == "binary" {
+                            let mut cmd =
+                                format!("MSG binary {} 10\r\n", sid)
+                                    .into_bytes();
+                            cmd.extend_from_slice(b" \xff\r\nMSG \x00\n\r\n");
+                            socket.write_all(&cmd).expect("Unable to write");
+                        }
+
+                        // Headers with a repeated name before the payload
+                        if subject == "headers" {
+                            let block = "NATS/1.0\r\nTrace-Id: 1\r\n\
+                                         Accept: a\r\nAccept: b\r\n\r\n";
+                            let cmd = format!(
+                                "HMSG headers {} {} {}\r\n{}KNOCK\r\n",
+                                sid,
+                                block.len(),
+                                block.len() + 5,
+                                block
+                            );
+                            socket
+                                .write_all(cmd.as_bytes())
+                                .expect("Unable to write");
+                        }
+
+                        // Our own message reflected back by the server
+                        if subject == "echo" {
+                            let block =
+                                "NATS/1.0\r\nNats-Bridge-Origin: bridge-1\r\n\r\n";
+                            let cmd = format!(
+                                "HMSG echo {} {} {}\r\n{}ECHO\r\n",
+                                sid,
+                                block.len(),
+                                block.len() + 4,
+                                block
+                            );
+                            socket
+                                .write_all(cmd.as_bytes())
+                                .expect("Unable to write");
+                        }
+
+                        // Server errors and updates before the message
+                        let update = match subject {
+                            "slow" => "-ERR 'Slow Consumer'\r\n",
+                            "denied" => {
+                                "-ERR 'Permissions Violation \
+                                         for Subscription to denied'\r\n"
+                            }
+                            "info" => {
+                                "INFO {\"ldm\":true,\"connect_urls\":\
+                                       [\"10.0.0.2:4222\"]}\r\n+OK\r\n"
+                            }
+                            _ => "",
+                        };
+                        socket
+                            .write_all(update.as_bytes())
+                            .expect("Unable to write");
+
+                        // Request with a reply subject
+                        if subject == "request" {
+                            let cmd = format!(
+                                "MSG request {} _INBOX.reply 5\r\nKNOCK\r\n",
+                                sid
+                            );
+                            socket
+                                .write_all(cmd.as_bytes())
+                                .expect("Unable to write");
+                        }
+
+                        let cmd =
+                            format!("MSG {} {} 5\r\nKNOCK\r\n", subject, sid);
+                        socket
+                            .write_all(cmd.as_bytes())
+                            .expect("Unable to write");
+                    }
+                    kv if kv.starts_with("PUB $JS.API.CONSUMER.")
+                        && kv.contains(".KV_config") =>
+                    {
+                        let detail: Vec<_> = kv.split_whitespace().collect();
+                        let length: usize =
+                            detail[detail.len() - 1].parse().expect("Length");
+                        let mut payload = vec![0u8; length + 2];
+                        reader.read_exact(&mut payload).expect("Payload");
+                        let body = String::from_utf8_lossy(&payload);
+
+                        // Snapshot of a put and a delete, then nothing
+                        let ack = "$JS.ACK.KV_config.watch";
+                        let cmd = if detail[1].contains(".CREATE.") {
+                            lines.push(body.into());
+                            let response = "{\"name\":\"watch\"}";
+                            format!(
+                                "MSG {} {} {}\r\n{}\r\n",
+                                detail[2],
+                                inbox_sid,
+                                response.len(),
+                                response
+                            )
+                        } else {
+                            kv_step += 1;
+                            match kv_step {
+                                1 => format!(
+                                    "MSG $KV.config.devices.a {} \
+                                     {}.1.1.1.0.1 8\r\n{{\"on\":1}}\r\n",
+                                    inbox_sid, ack
+                                ),
+                                2 => {
+                                    let block =
+                                        "NATS/1.0\r\nKV-Operation: DEL\r\n\r\n";
+                                    format!(
+                                        "HMSG $KV.config.devices.b {} \
+                                         {}.1.2.2.0.0 {} {}\r\n{}\r\n",
+                                        inbox_sid,
+                                        ack,
+                                        block.len(),
+                                        block.len(),
+                                        block
+                                    )
+                                }
+                                _ => {
+                                    let status =
+                                        "NATS/1.0 408 Request Timeout\r\n\r\n";
+                                    format!(
+                                        "HMSG {} {} {} {}\r\n{}\r\n",
+                                        detail[2],
+                                        inbox_sid,
+                                        status.len(),
+                                        status.len(),
+                                        status
+                                    )
+                                }
+                            }
+                        };
+                        reader
+                            .get_mut()
+                            .write_all(cmd.as_bytes())
+                            .expect("Unable to write");
+                    }
+                    api if api.starts_with("PUB $JS.") => {
+                        let detail: Vec<_> = api.split_whitespace().collect();
+                        let length: usize =
+                            detail[detail.len() - 1].parse().expect("Length");
+                        let mut payload = vec![0u8; length + 2];
+                        reader.read_exact(&mut payload).expect("Payload");
+                        let body = String::from_utf8_lossy(&payload);
+
+                        // Durable consumer with one message until acked
+                        let subject = detail[1];
+                        let cmd = if subject.starts_with("$JS.ACK.") {
+                            lines.push(body.into());
+                            acked = true;
+                            continue;
+                        } else if subject.starts_with("$JS.API.CONSUMER.") {
+                            let response = if subject.contains(".CREATE.") {
+                                if body.contains(
+                                    "\"filter_subject\":\"root.demo\"",
+                                ) {
+                                    "{\"name\":\"bridge\"}"
+                                } else {
+                                    "{\"error\":{\"code\":400,\
+                                     \"description\":\"bad filter\"}}"
+                                }
+                                .to_string()
+                            } else {
+                                String::new()
+                            };
+                            if !response.is_empty() {
+                                format!(
+                                    "MSG {} {} {}\r\n{}\r\n",
+                                    detail[2],
+                                    inbox_sid,
+                                    response.len(),
+                                    response
+                                )
+                            } else if acked {
+                                let status =
+                                    "NATS/1.0 404 No Messages\r\n\r\n";
+                                format!(
+                                    "HMSG {} {} {} {}\r\n{}\r\n",
+                                    detail[2],
+                                    inbox_sid,
+                                    status.len(),
+                                    status.len(),
+                                    status
+                                )
+                            } else {
+                                format!(
+                                    "MSG root.demo {} {} 5\r\nKNOCK\r\n",
+                                    inbox_sid,
+                                    "$JS.ACK.ORDERS.bridge.1.1.1.0.0"
+                                )
+                            }
+                        } else {
+                            continue;
+                        };
+                        reader
+                            .get_mut()
+                            .write_all(cmd.as_bytes())
+                            .expect("Unable to write");
+                    }
+                    publish if publish.starts_with("PUB ") => {
+                        let detail: Vec<_> =
+                            publish.split_whitespace().collect();
+                        let length: usize =
+                            detail[detail.len() - 1].parse().expect("Length");
+                        let mut payload = vec![0u8; length + 2];
+                        reader.read_exact(&mut payload).expect("Payload");
+                        lines.push(String::from_utf8_lossy(&payload).into());
+
+                        // Requests are answered with an echo to the inbox
+                        let mut cmd = match detail.len() {
+                            4 if detail[1] == "silent" => continue,
+                            4 => format!(
+                                "MSG {} {} {}\r\n",
+                                detail[2], inbox_sid, length
+                            ),
+                            _ => {
+                                format!("MSG {} 1 {}\r\n", detail[1], length)
+                            }
+                        }
+                        .into_bytes();
+                        cmd.extend_from_slice(&payload);
+                        reader
+                            .get_mut()
+                            .write_all(&cmd)
+                            .expect("Unable to write");
+                    }
+                    publish if publish.starts_with("HPUB ") => {
+                        let detail: Vec<_> =
+                            publish.split_whitespace().collect();
+                        let (header_length, length) = (
+                            detail[detail.len() - 2],
+                            detail[detail.len() - 1],
+                        );
+                        let header_length: usize =
+                            header_length.parse().expect("Header Length");
+                        let length: usize = length.parse().expect("Length");
+                        let mut payload = vec![0u8; length + 2];
+                        reader.read_exact(&mut payload).expect("Payload");
+                        lines.push(String::from_utf8_lossy(&payload).into());
+
+                        // The header block is framed by its own length
+                        let block = &payload[..header_length];
+
+                        // JetStream acknowledges stored messages
+                        let cmd = if detail.len() == 5 {
+                            let block = String::from_utf8_lossy(block);
+                            let ack = match detail[1] {
+                                "nostream" => {
+                                    let status = "NATS/1.0 503\r\n\r\n";
+                                    format!(
+                                        "HMSG {} {} {} {}\r\n{}\r\n",
+                                        detail[2],
+                                        inbox_sid,
+                                        status.len(),
+                                        status.len(),
+                                        status
+                                    )
+                                }
+                                _ => {
+                                    let duplicate =
+                                        block.contains("Nats-Msg-Id: dup");
+                                    if !duplicate {
+                                        sequence += 1;
+                                    }
+                                    let ack = format!(
+                                        "{{\"stream\":\"BRIDGE\",\
+                                         \"seq\":{},\"duplicate\":{}}}",
+                                        sequence, duplicate
+                                    );
+                                    format!(
+                                        "MSG {} {} {}\r\n{}\r\n",
+                                        detail[2],
+                                        inbox_sid,
+                                        ack.len(),
+                                        ack
+                                    )
+                                }
+                            };
+                            ack.into_bytes()
+                        } else {
+                            let mut cmd = format!(
+                                "HMSG {} 1 {} {}\r\n",
+                                detail[1], header_length, length
+                            )
+                            .into_bytes();
+                            cmd.extend_from_slice(&payload);
+                            cmd
+                        };
+                        reader
+                            .get_mut()
+                            .write_all(&cmd)
+                            .expect("Unable to write");
+                    }
+                    _ => eprintln!("Unexpected line: `{}`", line),
+                };
+            }
+            lines
+        }
+    }
+
+    // Self-signed CA with server and client certificates for the TLS mock
+    // Shared with the PubNub HTTPS tests
+    pub(crate) struct Certificates {
+        pub(crate) ca_file: String,
+        pub(crate) cert_file: String,
+        pub(crate) key_file: String,
+        pub(crate) acceptor: SslAcceptor,
+    }
+
+    fn certificate(
+        name: &str,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+    ) -> X509 {
+        let mut subject = X509NameBuilder::new().expect("X509 Name");
+        subject
+            .append_entry_by_text("CN", name)
+            .expect("X509 Common Name");
+        let subject = subject.build();
+
+        let mut builder = X509::builder().expect("X509 Builder");
+        builder.set_version(2).expect("X509 Version");
+        let mut serial = BigNum::new().expect("Serial");
+        serial
+            .rand(64, MsbOption::MAYBE_ZERO, false)
+            .expect("Serial");
+        builder
+            .set_serial_number(&serial.to_asn1_integer().expect("Serial"))
+            .expect("X509 Serial");
+        builder.set_subject_name(&subject).expect("X509 Subject");
+        builder.set_pubkey(key).expect("X509 Public Key");
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).expect("Time"))
+            .expect("X509 Not Before");
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).expect("Time"))
+            .expect("X509 Not After");
+
+        match issuer {
+            None => {
+                builder.set_issuer_name(&subject).expect("X509 Issuer");
+                let ca = BasicConstraints::new().critical().ca().build();
+                builder.append_extension(ca.expect("CA")).expect("CA");
+                builder.sign(key, MessageDigest::sha256()).expect("Signed");
+            }
+            Some((ca, ca_key)) => {
+                builder
+                    .set_issuer_name(ca.subject_name())
+                    .expect("X509 Issuer");
+                let alt_name = SubjectAlternativeName::new()
+                    .dns("localhost")
+                    .ip("127.0.0.1")
+                    .build(&builder.x509v3_context(Some(ca), None));
+                builder
+                    .append_extension(alt_name.expect("SAN"))
+                    .expect("SAN");
+                builder
+                    .sign(ca_key, MessageDigest::sha256())
+                    .expect("Signed");
+            }
+        };
+
+        builder.build()
+    }
+
+    pub(crate) fn certificates(name: &str) -> Certificates {
+        let directory = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(&directory).expect("Certificate directory");
+        let path = |file: &str| directory.join(file).display().to_string();
+        let key = || {
+            let group =
+                EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).expect("EC");
+            let ec = EcKey::generate(&group).expect("EC Key");
+            PKey::from_ec_key(ec).expect("Private Key")
+        };
+
+        let ca_key = key();
+        let ca = certificate("NATS Test CA", &ca_key, None);
+        let server_key = key();
+        let server =
+            certificate("localhost", &server_key, Some((&ca, &ca_key)));
+        let client_key = key();
+        let client =
+            certificate("nats-bridge", &client_key, Some((&ca, &ca_key)));
+
+        let ca_file = path("ca.pem");
+        let cert_file = path("client-cert.pem");
+        let key_file = path("client-key.pem");
+        std::fs::write(&ca_file, ca.to_pem().expect("PEM")).expect("CA");
+        std::fs::write(&cert_file, client.to_pem().expect("PEM"))
+            .expect("Client Certificate");
+        std::fs::write(
+            &key_file,
+            client_key.private_key_to_pem_pkcs8().expect("PEM"),
+        )
+        .expect("Client Key");
+
+        // Server requires a client certificate signed by the same CA
+        let mut acceptor =
+            SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+                .expect("SSL Acceptor");
+        acceptor.set_private_key(&server_key).expect("Server Key");
+        acceptor
+            .set_certificate(&server)
+            .expect("Server Certificate");
+        acceptor.set_ca_file(&ca_file).expect("CA");
+        acceptor.set_verify(
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+        );
+
+        Certificates {
+            ca_file,
+            cert_file,
+            key_file,
+            acceptor: acceptor.build(),
+        }
+    }
+
+    #[test]
+    fn publish_ok() {
+        let host = "0.0.0.0:4220";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let subject = "demo";
+        let root = "";
+        let mut publisher =
+            PublishClient::new(host, root, &Options::default())
+                .expect("NATS Publish Client");
+
+        publisher.publish(subject, "Hello").expect("Message Sent");
+        publisher.exit().expect("NATS Connection Closed");
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn subscribe_ok() {
+        let host = "0.0.0.0:4221";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let subject = "demo";
+        let root = "";
+        let mut subscriber =
+            SubscribeClient::new(host, root, subject, &Options::default())
+                .expect("NATS Subscribe Client");
+        let result = subscriber.next_message();
+        assert!(result.is_ok());
+        let message = result.expect("Received Message");
+        assert!(!message.subject.is_empty());
+        subscriber.exit().expect("NATS Socket Closed");
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn ping_ok() {
+        let host = "0.0.0.0:4223";
+        let root = "";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let mut nats = PublishClient::new(host, root, &Options::default())
+            .expect("NATS Publish Client");
+
+        let pong = nats.ping().expect("Pong from Ping");
+        assert_eq!(pong, "PONG\r\n");
+
+        nats.exit().expect("NATS Connection Closed");
+        t.join().expect("Thread died early...");
+    }
+
+    #[test]
+    fn connect_ok() {
+        let host = "0.0.0.0:4224";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || mock.process());
+
+        let options = Options {
+            name: "nats-bridge".into(),
+            user: "user".into(),
+            password: "password".into(),
+            ..Options::default()
+        };
+        let mut nats =
+            PublishClient::new(host, "", &options).expect("NATS Connected");
+
+        nats.exit().expect("NATS Connection Closed");
+        let lines = t.join().expect("Thread died early...");
+        let connect = &lines[0];
+        assert!(connect.starts_with("CONNECT {"));
+        assert!(connect.contains("\"name\":\"nats-bridge\""));
+        assert!(connect.contains("\"user\":\"user\""));
+        assert!(connect.contains("\"pass\":\"password\""));
+        assert!(!connect.contains("\"auth_token\""));
+    }
+
+    #[test]
+    fn connect_auth_token_ok() {
+        let host = "0.0.0.0:4254";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || mock.process());
+
+        let options = Options {
+            auth_token: "s3cr3t".into(),
+            ..Options::default()
+        };
+        let mut nats =
+            PublishClient::new(host, "", &options).expect("NATS Connected");
+
+        nats.exit().expect("NATS Connection Closed");
+        let lines = t.join().expect("Thread died early...");
+        let connect = &lines[0];
+        assert!(connect.contains("\"auth_token\":\"s3cr3t\""));
+        assert!(!connect.contains("\"user\""));
+        assert!(!connect.contains("\"pass\""));
+    }
+
+    #[test]
+    fn connect_authorization_violation() {
+        let host = "0.0.0.0:4225";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let options = Options {
+            auth_token: "bad".into(),
+            ..Options::default()
+        };
+        let result = PublishClient::new(host, "", &options);
+        match result {
+            Err(Error::AuthorizationViolation) => {}
+            _ => panic!("Expected an Authorization Violation"),
+        };
+
+        t.join().expect("Thread died early...");
+    }
+
+    #[test]
+    fn resubscribe_authorization_violation() {
+        let host = "0.0.0.0:4255";
+        let listener = TcpListener::bind(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            // Accept the first connection then drop it after the SUB
+            let (mut socket, _addr) = listener.accept().expect("Client");
+            socket.write_all(INFO.as_bytes()).expect("Info");
+            let mut reader = BufReader::new(socket);
+            let mut line = String::new();
+            while !line.starts_with("SUB ") {
+                line.clear();
+                reader.read_line(&mut line).expect("Line");
+                if line == "PING\r\n" {
+                    reader.get_mut().write_all(b"PONG\r\n").expect("Pong");
+                }
+            }
+            drop(reader);
+
+            // Credentials revoked before the reconnect
+            let (mut socket, _addr) = listener.accept().expect("Client");
+            socket.write_all(INFO.as_bytes()).expect("Info");
+            let mut reader = BufReader::new(socket);
+            line.clear();
+            reader.read_line(&mut line).expect("Connect");
+            reader
+                .get_mut()
+                .write_all(b"-ERR 'Authorization Violation'\r\n")
+                .expect("Error");
+            line
+        });
+
+        let options = Options::default();
+        let mut nats = SubscribeClient::new(host, "", "demo", &options)
+            .expect("NATS Subscribe Client");
+
+        match nats.next_event() {
+            Err(Error::AuthorizationViolation) => {}
+            Err(error) => panic!("Unexpected {:?}", error),
+            Ok(_) => panic!("Expected an Authorization Violation"),
+        };
+        assert!(!nats.socket.is_connected());
+        let connect = t.join().expect("Thread died early...");
+        assert!(connect.starts_with("CONNECT "));
+    }
+
+    #[test]
+    fn keepalive_missed_pong() {
+        let host = "0.0.0.0:4256";
+        let listener = TcpListener::bind(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            // Answer the handshake PING then stop answering
+            let (mut socket, _addr) = listener.accept().expect("Client");
+            socket.write_all(INFO.as_bytes()).expect("Info");
+            let mut silent = BufReader::new(socket);
+            let mut line = String::new();
+            let mut pings = 0;
+            while pings < 2 {
+                line.clear();
+                silent.read_line(&mut line).expect("Line");
+                if line == "PING\r\n" {
+                    pings += 1;
+                    if pings == 1 {
+                        silent
+                            .get_mut()
+                            .write_all(b"PONG\r\n")
+                            .expect("Pong");
+                    }
+                }
+            }
+
+            // The client gives up on the silent connection
+            let (mut socket, _addr) = listener.accept().expect("Client");
+            socket.write_all(INFO.as_bytes()).expect("Info");
+            let mut reader = BufReader::new(socket);
+            while !line.starts_with("SUB ") {
+                line.clear();
+                reader.read_line(&mut line).expect("Line");
+                if line == "PING\r\n" {
+                    reader.get_mut().write_all(b"PONG\r\n").expect("Pong");
+                }
+            }
+            reader
+                .get_mut()
+                .write_all(b"MSG demo 1 5\r\nKNOCK\r\n")
+                .expect("Message");
+            line.clear();
+            reader.read_line(&mut line).expect("Exit");
+            drop(silent);
+        });
+
+        let options = Options::default();
+        let mut nats = SubscribeClient::new(host, "", "demo", &options)
+            .expect("NATS Subscribe Client");
+        nats.set_timeout(1);
+        nats.keepalive = Duration::from_millis(100);
+
+        // PING, then the missed PONG reconnects and subscribes again
+        let mut payload = Vec::new();
+        for _ in 0..5 {
+            match nats.next_event() {
+                Ok(Event::Message(message)) => {
+                    payload = message.payload;
+                    break;
+                }
+                Ok(_) | Err(Error::Timeout) => continue,
+                Err(error) => panic!("Unexpected {:?}", error),
+            }
+        }
+        assert_eq!(payload, b"KNOCK");
+
+        nats.exit().expect("NATS Socket Closed");
+        t.join().expect("Thread died early...");
+    }
+
+    #[test]
+    fn tls_ok() {
+        let host = "127.0.0.1:4226";
+        let certificates = certificates("nats-bridge-tls-ok");
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let acceptor = certificates.acceptor.clone();
+        let t = thread::spawn(move || {
+            mock.process_tls(&acceptor);
+        });
+
+        let options = Options {
+            tls: Some(Tls {
+                ca_file: certificates.ca_file,
+                cert_file: certificates.cert_file,
+                key_file: certificates.key_file,
+                server_name: "localhost".into(),
+            }),
+            ..Options::default()
+        };
+        let mut nats =
+            PublishClient::new(host, "", &options).expect("NATS over TLS");
+
+        let pong = nats.ping().expect("Pong from Ping");
+        assert_eq!(pong, "PONG\r\n");
+
+        nats.exit().expect("NATS Connection Closed");
+        t.join().expect("Thread died early...");
+    }
+
+    #[test]
+    fn tls_server_name_mismatch() {
+        let host = "127.0.0.1:4227";
+        let certificates = certificates("nats-bridge-tls-mismatch");
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let acceptor = certificates.acceptor.clone();
+        let t = thread::spawn(move || {
+            mock.process_tls(&acceptor);
+        });
+
+        let options = Options {
+            tls: Some(Tls {
+                ca_file: certificates.ca_file,
+                cert_file: certificates.cert_file,
+                key_file: certificates.key_file,
+                server_name: "nats.example.com".into(),
+            }),
+            ..Options::default()
+        };
+        match PublishClient::new(host, "", &options) {
+            Err(Error::Tls) => {}
+            _ => panic!("Expected the server name to be rejected"),
+        };
+
+        t.join().expect("Thread died early...");
+    }
+
+    #[test]
+    fn subscribe_multiplexed_ok() {
+        let host = "0.0.0.0:4228";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let root = "root";
+        let mut subscriber =
+            SubscribeClient::new(host, root, "", &Options::default())
+                .expect("NATS Subscribe Client");
+        let demo = subscriber.subscribe("demo").expect("Subscribed");
+        let other = subscriber.subscribe("other").expect("Subscribed");
+        assert_ne!(demo, other);
+
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.sid, demo);
+        assert_eq!(message.subject, "demo");
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.sid, other);
+        assert_eq!(message.subject, "other");
+
+        subscriber.unsubscribe(&demo).expect("Unsubscribed");
+        assert!(subscriber.unsubscribe(&demo).is_err());
+
+        subscriber.exit().expect("NATS Socket Closed");
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn queue_subscribe_ok() {
+        let host = "0.0.0.0:4229";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let mut subscriber =
+            SubscribeClient::new(host, "", "", &Options::default())
+                .expect("NATS Subscribe Client");
+        let sid = subscriber
+            .queue_subscribe("demo", "bridges")
+            .expect("Subscribed");
+
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.sid, sid);
+        assert_eq!(message.subject, "demo");
+
+        subscriber.exit().expect("NATS Socket Closed");
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn subscribe_binary_payload_ok() {
+        let host = "0.0.0.0:4230";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let mut subscriber =
+            SubscribeClient::new(host, "", "binary", &Options::default())
+                .expect("NATS Subscribe Client");
+
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.payload, b" \xff\r\nMSG \x00\n");
+        assert_eq!(message.data(), None);
+
+        // The following message is still in sync
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.payload, b"KNOCK");
+        assert_eq!(message.data(), Some("KNOCK"));
+
+        subscriber.exit().expect("NATS Socket Closed");
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn subscribe_reply_to_ok() {
+        let host = "0.0.0.0:4231";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let mut subscriber =
+            SubscribeClient::new(host, "", "request", &Options::default())
+                .expect("NATS Subscribe Client");
+
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.reply_to, "_INBOX.reply");
+        assert_eq!(message.payload, b"KNOCK");
+
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.reply_to, "");
+
+        subscriber.exit().expect("NATS Socket Closed");
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn request_ok() {
+        let host = "0.0.0.0:4232";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let mut nats = PublishClient::new(host, "", &Options::default())
+            .expect("NATS Publish Client");
+
+        let timeout = Duration::from_secs(1);
+        let response =
+            nats.request("echo", "Hello", timeout).expect("Response");
+        assert!(response.subject.starts_with("_INBOX."));
+        assert_eq!(response.data(), Some("Hello"));
+
+        nats.exit().expect("NATS Connection Closed");
+        t.join().expect("Thread died early...");
+    }
+
+    #[test]
+    fn request_timeout() {
+        let host = "0.0.0.0:4233";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let mut nats = PublishClient::new(host, "", &Options::default())
+            .expect("NATS Publish Client");
+
+        let timeout = Duration::from_millis(200);
+        match nats.request("silent", "Hello", timeout) {
+            Err(Error::Timeout) => {}
+            _ => panic!("Expected a request timeout"),
+        };
+
+        // The connection is still usable after a timeout
+        let pong = nats.ping().expect("Pong from Ping");
+        assert_eq!(pong, "PONG\r\n");
+
+        nats.exit().expect("NATS Connection Closed");
+        t.join().expect("Thread died early...");
+    }
+
+    #[test]
+    fn publish_headers_ok() {
+        let host = "0.0.0.0:4234";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || mock.process());
+
+        let mut publisher = PublishClient::new(host, "", &Options::default())
+            .expect("NATS Publish Client");
+
+        let mut headers = Headers::new();
+        headers.insert("Content-Type".into(), vec!["text/plain".into()]);
+        headers.insert("Accept".into(), vec!["a".into(), "b".into()]);
+        publisher
+            .publish_with_headers("demo", &headers, "Hello")
+            .expect("Message Sent");
+
+        publisher.exit().expect("NATS Connection Closed");
+        let lines = t.join().expect("Mock TcpStream server");
+        let block = "NATS/1.0\r\nAccept: a\r\nAccept: b\r\n\
+                     Content-Type: text/plain\r\n\r\n";
+        let hpub =
+            format!("HPUB demo {} {}\r\n", block.len(), block.len() + 5);
+        let position = lines.iter().position(|line| *line == hpub);
+        let position = position.expect("HPUB sent");
+        assert_eq!(lines[position + 1], format!("{}Hello\r\n", block));
+    }
+
+    #[test]
+    fn subscribe_headers_ok() {
+        let host = "0.0.0.0:4235";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let mut subscriber =
+            SubscribeClient::new(host, "", "headers", &Options::default())
+                .expect("NATS Subscribe Client");
+
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.payload, b"KNOCK");
+        assert_eq!(message.headers["Trace-Id"], ["1"]);
+        assert_eq!(message.headers["Accept"], ["a", "b"]);
+        assert!(message.status.is_empty());
+
+        let message = subscriber.next_message().expect("Received Message");
+        assert!(message.headers.is_empty());
+
+        subscriber.exit().expect("NATS Socket Closed");
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn parse_headers_status_ok() {
+        let (status, description, headers) =
+            parse_headers(b"NATS/1.0 503\r\n\r\n");
+        assert_eq!(status, "503");
+        assert!(description.is_empty());
+        assert!(headers.is_empty());
+
+        let (status, description, _headers) =
+            parse_headers(b"NATS/1.0 404 No Messages\r\n\r\n");
+        assert_eq!(status, "404");
+        assert_eq!(description, "No Messages");
+
+        let (status, _description, headers) =
+            parse_headers(b"NATS/1.0\r\nA: 1\r\n\r\n");
+        assert!(status.is_empty());
+        assert_eq!(headers["A"], ["1"]);
+    }
+
+    #[test]
+    fn subscribe_server_errors() {
+        let host = "0.0.0.0:4236";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let mut subscriber =
+            SubscribeClient::new(host, "", "denied", &Options::default())
+                .expect("NATS Subscribe Client");
+        match subscriber.next_message() {
+            Err(Error::PermissionsViolation(message)) => assert_eq!(
+                message,
+                "Permissions Violation for Subscription to denied"
+            ),
+            _ => panic!("Expected a permissions violation"),
+        }
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.subject, "denied");
+
+        subscriber.subscribe("slow").expect("Subscribed");
+        match subscriber.next_message() {
+            Err(Error::SlowConsumer) => {}
+            _ => panic!("Expected a slow consumer error"),
+        }
+
+        subscriber.exit().expect("NATS Socket Closed");
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn subscribe_info_update_ok() {
+        let host = "0.0.0.0:4237";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let mut subscriber =
+            SubscribeClient::new(host, "", "info", &Options::default())
+                .expect("NATS Subscribe Client");
+        match subscriber.next_event() {
+            Ok(Event::Info(info)) => {
+                assert_eq!(info.connect_urls, vec!["10.0.0.2:4222"]);
+                assert!(info.lame_duck_mode);
+                assert!(subscriber.server_info().lame_duck_mode);
+                assert!(subscriber
+                    .socket
+                    .servers()
+                    .contains(&"10.0.0.2:4222".to_string()));
+            }
+            _ => panic!("Expected an INFO update"),
         }
+        match subscriber.next_event() {
+            Ok(Event::Ok) => {}
+            _ => panic!("Expected +OK"),
+        }
+
+        // Updates are skipped when waiting for messages
+        subscriber.subscribe("info").expect("Subscribed");
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.subject, "info");
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.subject, "info");
+
+        subscriber.exit().expect("NATS Socket Closed");
+        t.join().expect("Mock TcpStream server");
     }
-}
 
-impl Drop for PublishClient {
-    fn drop(&mut self) {
-        self.socket.disconnect();
+    #[test]
+    fn subscribe_failover_ok() {
+        let hosts = "127.0.0.1:4238, 127.0.0.1:4239";
+        let mut mocks = Vec::new();
+        for host in hosts.split(", ") {
+            let mock = NATSMock::new(host).expect("Unable to listen");
+            mocks.push(thread::spawn(move || {
+                mock.process();
+            }));
+        }
+
+        let mut subscriber =
+            SubscribeClient::new(hosts, "", "demo", &Options::default())
+                .expect("NATS Subscribe Client");
+        let first = subscriber.socket.host().to_string();
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.payload, b"KNOCK");
+
+        // The first server goes away and the other takes over
+        subscriber.exit().expect("NATS Socket Closed");
+        assert!(subscriber.next_message().is_err());
+        assert_ne!(subscriber.socket.host(), first);
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.subject, "demo");
+
+        subscriber.exit().expect("NATS Socket Closed");
+        for mock in mocks {
+            mock.join().expect("Mock TcpStream server");
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::{BufRead, BufReader, Write};
-    use std::net::TcpListener;
-    use std::thread;
+    #[test]
+    fn connect_credentials_ok() {
+        let host = "0.0.0.0:4240";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process_nonce();
+        });
 
-    struct NATSMock {
-        listener: TcpListener,
+        let directory = std::env::temp_dir().join("nats-bridge-creds");
+        std::fs::create_dir_all(&directory).expect("Credentials directory");
+        let credentials = directory.join("user.creds");
+        std::fs::write(&credentials, CREDS).expect("Credentials file");
+        let options = Options {
+            credentials: credentials.display().to_string(),
+            ..Options::default()
+        };
+
+        let mut publisher =
+            PublishClient::new(host, "", &options).expect("Signed nonce");
+        publisher.publish("demo", "Hello").expect("Message Sent");
+        publisher.exit().expect("NATS Connection Closed");
+        t.join().expect("Mock TcpStream server");
     }
 
-    impl NATSMock {
-        fn new(host: &str) -> std::io::Result<Self> {
-            let listener = TcpListener::bind(host)?;
-            Ok(Self { listener })
+    #[test]
+    fn connect_credentials_missing() {
+        let host = "0.0.0.0:4241";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process_nonce();
+        });
+
+        let options = Options {
+            credentials: "/nonexistent/user.creds".into(),
+            ..Options::default()
+        };
+        match PublishClient::new(host, "", &options) {
+            Err(Error::Credentials) => {}
+            _ => panic!("Expected a credentials error"),
         }
+        t.join().expect("Mock TcpStream server");
+    }
 
-        fn process(&self) {
-            match self.listener.accept() {
-                Ok((mut socket, _addr)) => {
-                    socket.write_all(b"INFO {\"server_id\":\"asbLGfs3r7pgZwucUxYnPn\",\"version\":\"1.4.1\",\"proto\":1,\"git_commit\":\"3e64f0b\",\"go\":\"go1.11.5\",\"host\":\"0.0.0.0\",\"port\":4222,\"max_payload\":1048576,\"client_id\":9999}\r\n").expect("Could not send info");
-
-                    let mut reader = BufReader::new(
-                        socket.try_clone().expect("Unable to clone socket"),
-                    );
-                    let mut line = String::new();
-
-                    loop {
-                        line.clear();
-                        let size = reader
-                            .read_line(&mut line)
-                            .expect("Unable to read line");
-                        if size == 0 {
-                            eprintln!("Socket disconnected while reading");
-                            break;
-                        }
+    #[test]
+    fn jetstream_publish_ok() {
+        let host = "0.0.0.0:4242";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
 
-                        match line.as_ref() {
-                            "EXIT\r\n" => break,
-                            "PING\r\n" => {
-                                socket
-                                    .write_all(b"PONG\r\n")
-                                    .expect("Unable to write");
-                            }
-                            "SUB demo 9999\r\n" => {
-                                socket
-                                    .write_all(
-                                        b"MSG demo 9999 5\r\nKNOCK\r\n",
-                                    )
-                                    .expect("Unable to write");
-                            }
-                            "PUB demo 5\r\n" => {
-                                line.clear();
-                                reader
-                                    .read_line(&mut line)
-                                    .expect("Unable to read line");
-
-                                let cmd = format!("MSG demo 1 1\r\n{}", line);
-                                socket
-                                    .write_all(cmd.as_bytes())
-                                    .expect("Unable to write");
-                            }
-                            _ => eprintln!("Unexpected line: `{}`", line),
-                        };
-                    }
-                }
-                Err(e) => eprintln!("couldn't get client: {:?}", e),
+        let mut publisher = PublishClient::new(host, "", &Options::default())
+            .expect("NATS Publish Client");
+        let timeout = Duration::from_secs(1);
+
+        let mut headers = Headers::new();
+        headers
+            .insert("Nats-Msg-Id".into(), vec!["16195023423187265".into()]);
+        let ack = publisher
+            .jetstream_publish("orders", &headers, "{}", timeout)
+            .expect("PubAck");
+        assert_eq!(ack.stream, "BRIDGE");
+        assert_eq!(ack.sequence, 1);
+        assert!(!ack.duplicate);
+
+        // Retried publishes are acknowledged without storing them again
+        headers.insert("Nats-Msg-Id".into(), vec!["dup".into()]);
+        let ack = publisher
+            .jetstream_publish("orders", &headers, "{}", timeout)
+            .expect("PubAck");
+        assert_eq!(ack.sequence, 1);
+        assert!(ack.duplicate);
+
+        publisher.exit().expect("NATS Connection Closed");
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn jetstream_no_responders() {
+        let host = "0.0.0.0:4243";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let mut publisher = PublishClient::new(host, "", &Options::default())
+            .expect("NATS Publish Client");
+
+        let mut headers = Headers::new();
+        headers
+            .insert("Nats-Msg-Id".into(), vec!["16195023423187265".into()]);
+        let timeout = Duration::from_secs(1);
+        match publisher.jetstream_publish("nostream", &headers, "{}", timeout)
+        {
+            Err(Error::NoResponders) => {}
+            _ => panic!("Expected no responders"),
+        }
+
+        publisher.exit().expect("NATS Connection Closed");
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn jetstream_consumer_ok() {
+        let host = "0.0.0.0:4244";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || mock.process());
+
+        let options = Options::default();
+        let mut consumer = Consumer::new(
+            host,
+            "root",
+            "ORDERS",
+            "bridge",
+            &["demo"],
+            &options,
+        )
+        .expect("JetStream Consumer");
+        let wait = Duration::from_millis(200);
+
+        let message = consumer.next_message(wait).expect("Received Message");
+        assert_eq!(message.subject, "demo");
+        assert_eq!(message.root, "root");
+        assert_eq!(message.payload, b"KNOCK");
+
+        // Delivered again until acknowledged
+        let message = consumer.next_message(wait).expect("Redelivered");
+        assert_eq!(message.reply_to, "$JS.ACK.ORDERS.bridge.1.1.1.0.0");
+        consumer.ack(&message).expect("Acknowledged");
+
+        match consumer.next_message(wait) {
+            Err(Error::Timeout) => {}
+            _ => panic!("Expected no messages"),
+        }
+
+        consumer.client.exit().expect("NATS Connection Closed");
+        let lines = t.join().expect("Mock TcpStream server");
+        assert!(lines.contains(&"+ACK\r\n".to_string()));
+    }
+
+    #[test]
+    fn jetstream_consumer_term() {
+        let host = "0.0.0.0:4259";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || mock.process());
+
+        let options = Options::default();
+        let mut consumer = Consumer::new(
+            host,
+            "",
+            "ORDERS",
+            "bridge",
+            &["root.demo"],
+            &options,
+        )
+        .expect("JetStream Consumer");
+        let wait = Duration::from_millis(200);
+
+        // Not delivered again once terminated
+        let message = consumer.next_message(wait).expect("Received Message");
+        consumer.term(&message).expect("Terminated");
+        match consumer.next_message(wait) {
+            Err(Error::Timeout) => {}
+            _ => panic!("Expected no messages"),
+        }
+
+        consumer.client.exit().expect("NATS Connection Closed");
+        let lines = t.join().expect("Mock TcpStream server");
+        assert!(lines.contains(&"+TERM\r\n".to_string()));
+        assert!(!lines.contains(&"+ACK\r\n".to_string()));
+    }
+
+    #[test]
+    fn jetstream_consumer_error() {
+        let host = "0.0.0.0:4245";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let options = Options::default();
+        match Consumer::new(host, "", "ORDERS", "bridge", &["demo"], &options)
+        {
+            Err(Error::JetStream(description)) => {
+                assert_eq!(description, "bad filter")
             }
+            _ => panic!("Expected a JetStream API error"),
         }
+        t.join().expect("Mock TcpStream server");
     }
 
     #[test]
-    fn publish_ok() {
-        let host = "0.0.0.0:4220";
+    fn kv_watch_ok() {
+        let host = "0.0.0.0:4246";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || mock.process());
+
+        let mut watcher = Watcher::new(host, "config", &Options::default())
+            .expect("Key-Value Watcher");
+        let wait = Duration::from_millis(200);
+
+        let entry = watcher.next_entry(wait).expect("Snapshot Entry");
+        assert_eq!(entry.bucket, "config");
+        assert_eq!(entry.key, "devices.a");
+        assert_eq!(entry.value, b"{\"on\":1}");
+        assert_eq!(entry.operation, Operation::Put);
+        assert_eq!((entry.revision, entry.pending), (1, 1));
+
+        let entry = watcher.next_entry(wait).expect("Snapshot Entry");
+        assert_eq!(entry.key, "devices.b");
+        assert!(entry.value.is_empty());
+        assert_eq!(entry.operation, Operation::Delete);
+        assert_eq!((entry.revision, entry.pending), (2, 0));
+
+        match watcher.next_entry(wait) {
+            Err(Error::Timeout) => {}
+            _ => panic!("Expected no changes"),
+        }
+
+        watcher
+            .consumer
+            .client
+            .exit()
+            .expect("NATS Connection Closed");
+        let lines = t.join().expect("Mock TcpStream server");
+        let create = lines
+            .iter()
+            .find(|line| line.contains("\"deliver_policy\""))
+            .expect("Consumer created");
+        assert!(create.contains("last_per_subject"));
+        assert!(create.contains("\"$KV.config.>\""));
+    }
+
+    #[test]
+    fn ack_metadata_ok() {
+        let v1 = "$JS.ACK.KV_config.watch.1.27.3.1700000000000000000.4";
+        assert_eq!(ack_metadata(v1), (27, 4));
+
+        let v2 = "$JS.ACK.hub.ACCHASH.KV_config.watch.1.27.3.\
+                  1700000000000000000.4.8kNb";
+        assert_eq!(ack_metadata(v2), (27, 4));
+    }
+
+    #[test]
+    fn subscribe_drain_ok() {
+        let host = "0.0.0.0:4247";
         let mock = NATSMock::new(host).expect("Unable to listen");
         let t = thread::spawn(move || {
             mock.process();
         });
 
-        let subject = "demo";
-        let root = "";
-        let mut publisher =
-            PublishClient::new(host, root).expect("NATS Publish Client");
+        let subscriber =
+            SubscribeClient::new(host, "", "demo", &Options::default())
+                .expect("NATS Subscribe Client");
+
+        // The message sent before the UNSUB is not lost
+        let messages = subscriber.drain().expect("Drained");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].payload, b"KNOCK");
 
-        publisher.publish(subject, "Hello").expect("Message Sent");
-        publisher.exit().expect("NATS Connection Closed");
         t.join().expect("Mock TcpStream server");
     }
 
     #[test]
-    fn subscribe_ok() {
-        let host = "0.0.0.0:4221";
+    fn publish_drain_ok() {
+        let host = "0.0.0.0:4248";
         let mock = NATSMock::new(host).expect("Unable to listen");
         let t = thread::spawn(move || {
             mock.process();
         });
 
-        let subject = "demo";
-        let root = "";
-        let mut subscriber = SubscribeClient::new(host, root, subject)
+        let mut publisher = PublishClient::new(host, "", &Options::default())
+            .expect("NATS Publish Client");
+        publisher.publish("demo", "Goodbye").expect("Message Sent");
+        publisher.drain().expect("Drained");
+
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn publish_payload_too_large() {
+        let host = "0.0.0.0:4250";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let mut publisher = PublishClient::new(host, "", &Options::default())
+            .expect("NATS Publish Client");
+        let info = publisher.server_info();
+        assert_eq!(info.server_id, "asbLGfs3r7pgZwucUxYnPn");
+        assert_eq!(info.proto, 1);
+        assert_eq!(info.max_payload, 1048576);
+        assert!(info.headers);
+
+        let payload = vec![b'x'; 1048577];
+        match publisher.publish("demo", &payload) {
+            Err(Error::PayloadTooLarge) => {}
+            _ => panic!("Expected a payload too large error"),
+        }
+        let subject = "x".repeat(MAX_CONTROL_LINE);
+        match publisher.publish(subject, "Hello") {
+            Err(Error::ControlLineTooLong) => {}
+            _ => panic!("Expected a control line too long error"),
+        }
+
+        // Servers without header support never see HPUB
+        publisher.info.headers = false;
+        let mut headers = Headers::new();
+        headers.insert("Trace-Id".into(), vec!["4bf92f3577b34da6".into()]);
+        match publisher.publish_with_headers("demo", &headers, "Hello") {
+            Err(Error::Publish) => {}
+            _ => panic!("Expected a publish error"),
+        }
+
+        // Nothing was sent, the connection is still open
+        let pong = publisher.ping().expect("PONG");
+        assert_eq!(pong, "PONG\r\n");
+        publisher.exit().expect("EXIT");
+
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn subscribe_origin_dropped() {
+        let host = "0.0.0.0:4251";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let options = Options {
+            no_echo: true,
+            origin: "bridge-1".into(),
+            ..Options::default()
+        };
+        let mut subscriber = SubscribeClient::new(host, "", "echo", &options)
             .expect("NATS Subscribe Client");
-        let result = subscriber.next_message();
-        assert!(result.is_ok());
-        let message = result.expect("Received Message");
-        assert!(message.subject.len() > 0);
+
+        // The reflected ECHO is skipped
+        let message = subscriber.next_message().expect("Received Message");
+        assert_eq!(message.payload, b"KNOCK");
         subscriber.exit().expect("NATS Socket Closed");
+
         t.join().expect("Mock TcpStream server");
     }
 
     #[test]
-    fn ping_ok() {
-        let host = "0.0.0.0:4223";
-        let root = "";
+    fn publish_origin_ok() {
+        let host = "0.0.0.0:4258";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || mock.process());
+
+        let options = Options {
+            no_echo: true,
+            origin: "bridge-1".into(),
+            ..Options::default()
+        };
+        let mut publisher =
+            PublishClient::new(host, "", &options).expect("NATS Connected");
+        publisher.publish("demo", "KNOCK").expect("Published");
+        publisher.reply("_INBOX.reply", "KNOCK").expect("Replied");
+        publisher
+            .request("service", "KNOCK", Duration::from_secs(2))
+            .expect("Response");
+        publisher.flush().expect("Flushed");
+        publisher.exit().expect("NATS Connection Closed");
+
+        let lines = t.join().expect("Mock TcpStream server");
+        assert!(lines[0].contains("\"echo\":false"));
+        let block = "NATS/1.0\r\nNats-Bridge-Origin: bridge-1\r\n\r\n";
+        let hpubs: Vec<&String> = lines
+            .iter()
+            .filter(|line| line.starts_with("HPUB "))
+            .collect();
+        assert_eq!(hpubs.len(), 3);
+        assert!(hpubs[0].starts_with("HPUB demo "));
+        assert!(hpubs[1].starts_with("HPUB _INBOX.reply "));
+        assert!(hpubs[2].starts_with("HPUB service _INBOX."));
+        let marked = lines.iter().filter(|line| line.starts_with(block));
+        assert_eq!(marked.count(), 3);
+    }
+
+    #[test]
+    fn publish_invalid_subject() {
+        let host = "0.0.0.0:4252";
         let mock = NATSMock::new(host).expect("Unable to listen");
         let t = thread::spawn(move || {
             mock.process();
         });
 
-        let mut nats =
-            PublishClient::new(host, root).expect("NATS Publish Client");
+        let mut publisher =
+            PublishClient::new(host, "root", &Options::default())
+                .expect("NATS Publish Client");
+        for subject in ["orders.*", "orders >", "orders new", ""] {
+            match publisher.publish(subject, "Hello") {
+                Err(Error::InvalidSubject(_)) => {}
+                _ => panic!("Expected an invalid subject: {:?}", subject),
+            }
+        }
+        match publisher.reply("_INBOX.>", "Hello") {
+            Err(Error::InvalidSubject(reply_to)) => {
+                assert_eq!(reply_to, "_INBOX.>")
+            }
+            _ => panic!("Expected an invalid reply subject"),
+        }
 
-        let pong = nats.ping().expect("Pong from Ping");
+        // Nothing was sent, the connection is still open
+        let pong = publisher.ping().expect("PONG");
         assert_eq!(pong, "PONG\r\n");
+        publisher.exit().expect("EXIT");
 
-        nats.exit().expect("NATS Connection Closed");
-        t.join().expect("Thread died early...");
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn subscribe_invalid_subject() {
+        let host = "0.0.0.0:4253";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            mock.process();
+        });
+
+        let mut subscriber =
+            SubscribeClient::new(host, "root", "", &Options::default())
+                .expect("NATS Subscribe Client");
+        match subscriber.subscribe("orders.>.new") {
+            Err(Error::InvalidSubject(subject)) => {
+                assert_eq!(subject, "root.orders.>.new")
+            }
+            _ => panic!("Expected an invalid subject"),
+        }
+        match subscriber.queue_subscribe("orders.*", "bridges *") {
+            Err(Error::InvalidSubject(_)) => {}
+            _ => panic!("Expected an invalid queue group"),
+        }
+        assert!(subscriber.subscriptions.is_empty());
+        subscriber.exit().expect("NATS Socket Closed");
+
+        t.join().expect("Mock TcpStream server");
+    }
+
+    #[test]
+    fn publish_flush_ok() {
+        let host = "0.0.0.0:4249";
+        let mock = NATSMock::new(host).expect("Unable to listen");
+        let t = thread::spawn(move || mock.process());
+
+        let options = Options {
+            flush_interval: Duration::from_secs(60),
+            ..Options::default()
+        };
+        let mut publisher = PublishClient::new(host, "", &options)
+            .expect("NATS Publish Client");
+
+        // Written in one batch by the flush
+        for count in 0..3 {
+            publisher
+                .publish("demo", format!("{}", count))
+                .expect("Sent");
+        }
+        assert_eq!(publisher.buffered, 3);
+        publisher.flush().expect("Flushed");
+        assert!(publisher.buffer.is_empty());
+
+        // Nothing left to confirm
+        publisher.flush().expect("Flushed");
+        publisher.exit().expect("EXIT");
+
+        // Every message ahead of the single PING
+        let lines = t.join().expect("Mock TcpStream server");
+        let sent: Vec<&str> = lines[2..].iter().map(String::as_str).collect();
+        assert_eq!(
+            sent,
+            [
+                "PUB demo 1\r\n",
+                "0\r\n",
+                "PUB demo 1\r\n",
+                "1\r\n",
+                "PUB demo 1\r\n",
+                "2\r\n",
+                "PING\r\n",
+                "EXIT\r\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn publish_flush_lost() {
+        let host = "0.0.0.0:4257";
+        let listener = TcpListener::bind(host).expect("Unable to listen");
+        let t = thread::spawn(move || {
+            let mut lines = Vec::new();
+            for connection in 0..2 {
+                let (mut socket, _addr) = listener.accept().expect("Client");
+                socket.write_all(INFO.as_bytes()).expect("Info");
+                let mut reader = BufReader::new(socket);
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line).expect("Line") == 0 {
+                        break;
+                    }
+                    lines.push(line.clone());
+                    match line.as_str() {
+                        // Gone before confirming the first message
+                        "lost\r\n" if connection == 0 => break,
+                        "PING\r\n" => reader
+                            .get_mut()
+                            .write_all(b"PONG\r\n")
+                            .expect("Pong"),
+                        "EXIT\r\n" => break,
+                        _ => {}
+                    }
+                }
+            }
+            lines
+        });
+
+        let mut publisher = PublishClient::new(host, "", &Options::default())
+            .expect("NATS Publish Client");
+        publisher.publish("demo", "lost").expect("Sent");
+
+        // No PONG, then the reconnect reports the message as lost
+        assert!(publisher.flush().is_err());
+        match publisher.flush() {
+            Err(Error::Publish) => {}
+            result => panic!("Expected a lost publish {:?}", result.err()),
+        }
+
+        publisher.publish("demo", "again").expect("Sent");
+        publisher.flush().expect("Flushed");
+        publisher.exit().expect("EXIT");
+
+        let lines = t.join().expect("Mock server");
+        assert_eq!(
+            lines.iter().filter(|line| *line == "lost\r\n").count(),
+            1
+        );
+        assert!(lines.contains(&"again\r\n".to_string()));
     }
 }
@@ -0,0 +1,209 @@
+use openssl::base64;
+use openssl::pkey::{Id, PKey};
+use openssl::sign::Signer;
+
+#[derive(Debug)]
+pub enum Error {
+    Read,
+    Jwt,
+    Seed,
+    Sign,
+}
+
+// Seed prefix of the NKEY encoding, the first 5 bits of the first byte
+const PREFIX_BYTE_SEED: u8 = 18 << 3;
+
+/// # NATS Credentials
+///
+/// The user JWT and NKEY seed from a `.creds` file, used to
+/// authenticate with NATS servers running in operator mode.
+/// The server sends a `nonce` in INFO which is signed with the seed.
+///
+/// ```no_run
+/// use nats_bridge::nkeys::Credentials;
+///
+/// let credentials = Credentials::from_file("/etc/nats/user.creds")
+///     .expect("NATS Credentials");
+/// let signature = credentials.sign("PXoWU7zWAMt75FY").expect("Signed");
+/// println!("{} {}", credentials.jwt, signature);
+/// ```
+pub struct Credentials {
+    pub jwt: String,
+    seed: Vec<u8>,
+}
+
+impl Credentials {
+    pub fn from_file(path: &str) -> Result<Self, Error> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(_error) => Err(Error::Read),
+        }
+    }
+
+    /// ## Parse Credentials
+    ///
+    /// Each part is the first line after its `-----BEGIN` marker.
+    ///
+    /// ```
+    /// use nats_bridge::nkeys::Credentials;
+    ///
+    /// let text = "-----BEGIN NATS USER JWT-----\n\
+    ///             eyJ0eXAiOiJKV1QiLCJhbGciOiJlZDI1NTE5LW5rZXkifQ\n\
+    ///             ------END NATS USER JWT------\n\
+    ///             -----BEGIN USER NKEY SEED-----\n\
+    ///             SUAACAQDAQCQMBYIBEFAWDANBYHRAEISCMKBKFQXDAMRUGY4DUPB6IC5CQ\n\
+    ///             ------END USER NKEY SEED------\n";
+    /// let credentials = Credentials::parse(text).expect("Credentials");
+    /// assert!(credentials.jwt.starts_with("eyJ0"));
+    /// ```
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let jwt = match section(text, "NATS USER JWT") {
+            Some(jwt) => jwt.to_string(),
+            None => return Err(Error::Jwt),
+        };
+        let seed = match section(text, "USER NKEY SEED") {
+            Some(seed) => decode_seed(seed)?,
+            None => return Err(Error::Seed),
+        };
+        Ok(Self { jwt, seed })
+    }
+
+    /// ## Sign Server Nonce
+    ///
+    /// Ed25519 signature of the nonce, base64 URL encoded for CONNECT.
+    pub fn sign(&self, nonce: &str) -> Result<String, Error> {
+        let key =
+            match PKey::private_key_from_raw_bytes(&self.seed, Id::ED25519) {
+                Ok(key) => key,
+                Err(_error) => return Err(Error::Seed),
+            };
+        let signature =
+            Signer::new_without_digest(&key).and_then(|mut signer| {
+                signer.sign_oneshot_to_vec(nonce.as_bytes())
+            });
+        match signature {
+            Ok(signature) => Ok(base64::encode_block(&signature)
+                .trim_end_matches('=')
+                .replace('+', "-")
+                .replace('/', "_")),
+            Err(_error) => Err(Error::Sign),
+        }
+    }
+}
+
+// First line after -----BEGIN <name>-----
+fn section<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    let marker = format!("-----BEGIN {}-----", name);
+    let mut lines = text.lines().map(str::trim);
+    lines.find(|line| *line == marker)?;
+    lines.find(|line| !line.is_empty())
+}
+
+// Seed is base32 of 2 prefix bytes, 32 raw seed bytes and a CRC16
+fn decode_seed(seed: &str) -> Result<Vec<u8>, Error> {
+    let raw = match base32_decode(seed) {
+        Some(raw) if raw.len() == 36 => raw,
+        _ => return Err(Error::Seed),
+    };
+    let (data, checksum) = raw.split_at(34);
+    if crc16(data).to_le_bytes() != checksum {
+        return Err(Error::Seed);
+    }
+    if data[0] & 0b1111_1000 != PREFIX_BYTE_SEED {
+        return Err(Error::Seed);
+    }
+    Ok(data[2..].to_vec())
+}
+
+// RFC 4648 base32 without padding
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for character in text.trim_end_matches('=').bytes() {
+        let value = match character {
+            b'A'..=b'Z' => character - b'A',
+            b'2'..=b'7' => character - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | u32::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+// CRC-16/XMODEM
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CREDS: &str = "-----BEGIN NATS USER JWT-----
+eyJ0eXAiOiJKV1QiLCJhbGciOiJlZDI1NTE5LW5rZXkifQ.eyJzdWIiOiJVIn0.c2ln
+------END NATS USER JWT------
+
+************************* IMPORTANT *************************
+NKEY Seed printed below can be used to sign and prove identity.
+
+-----BEGIN USER NKEY SEED-----
+SUAACAQDAQCQMBYIBEFAWDANBYHRAEISCMKBKFQXDAMRUGY4DUPB6IC5CQ
+------END USER NKEY SEED------
+";
+
+    #[test]
+    fn parse_ok() {
+        let credentials = Credentials::parse(CREDS).expect("Credentials");
+        assert_eq!(
+            credentials.jwt,
+            "eyJ0eXAiOiJKV1QiLCJhbGciOiJlZDI1NTE5LW5rZXkifQ.eyJzdWIiOiJVIn0.c2ln"
+        );
+        assert_eq!(credentials.seed, (1..=32).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn sign_ok() {
+        let credentials = Credentials::parse(CREDS).expect("Credentials");
+        let signature = credentials.sign("PXoWU7zWAMt75FY").expect("Signed");
+        assert_eq!(
+            signature,
+            "RkyUfhj0hZV4SGosA4w8Z2jQjAdZCALBJzhNdhfW6581WHG7tdqsG6pGlr5MATr_\
+             YsnnyJOjpr9o2S3IT6iUBA"
+        );
+    }
+
+    #[test]
+    fn seed_checksum_error() {
+        let creds = CREDS.replace("SUAACAQ", "SUAACAR");
+        match Credentials::parse(&creds) {
+            Err(Error::Seed) => {}
+            _ => panic!("Expected a seed checksum error"),
+        }
+    }
+
+    #[test]
+    fn missing_jwt_error() {
+        let creds = CREDS.replace("NATS USER JWT", "NATS USER");
+        match Credentials::parse(&creds) {
+            Err(Error::Jwt) => {}
+            _ => panic!("Expected a missing JWT error"),
+        }
+    }
+}
@@ -1,6 +1,16 @@
-use crate::socket::Socket;
+use crate::cipher::Cipher;
+use crate::socket::{self, Socket, Tls};
+use crate::subject;
+use flate2::read::{GzDecoder, ZlibDecoder};
 use json::JsonValue;
-use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub struct SubscribeClient {
     socket: Socket,
@@ -8,9 +18,13 @@ pub struct SubscribeClient {
     channel: String,
     messages: Vec<Message>,
     timetoken: String,
+    publish_key: String,
     subscribe_key: String,
-    _secret_key: String,
+    secret_key: String,
+    auth_key: String,
     agent: String,
+    cipher: Option<Cipher>,
+    undecrypted: bool,
 }
 
 pub struct PublishClient {
@@ -18,8 +32,37 @@ pub struct PublishClient {
     root: String,
     publish_key: String,
     subscribe_key: String,
-    _secret_key: String,
+    secret_key: String,
+    auth_key: String,
     agent: String,
+    cipher: Option<Cipher>,
+}
+
+/// # PubNub Connection Options
+///
+/// Setting `tls` sends every request over HTTPS with certificate validation.
+/// Use port `443` in the host when TLS is enabled.
+/// Setting `cipher` encrypts published messages and decrypts received
+/// ones, as the PubNub SDKs do with the same cipher key.
+/// Setting `auth_key` sends an Access Manager auth key or token with
+/// every request.
+///
+/// ```
+/// use nats_bridge::cipher::Cipher;
+/// use nats_bridge::pubnub::Options;
+/// use nats_bridge::socket::Tls;
+///
+/// let options = Options {
+///     tls: Some(Tls::default()),
+///     cipher: Some(Cipher::new("enigma", true).expect("Cipher")),
+///     auth_key: "".into(),
+/// };
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub tls: Option<Tls>,
+    pub cipher: Option<Cipher>,
+    pub auth_key: String,
 }
 
 pub struct Message {
@@ -30,6 +73,17 @@ pub struct Message {
     pub id: String,
 }
 
+/// # PubNub Errors
+///
+/// HTTP error statuses are decoded along with the `message` of the
+/// PubNub error body: `BadRequest` (400) for invalid keys or parameters,
+/// `AccessDenied` (403) from Access Manager, `PayloadTooLarge` (413),
+/// `URITooLong` (414) and `RateLimited` (429).
+/// Other statuses are returned as `HTTPStatus`.
+/// `Encrypt` is returned when a message can not be encrypted with the
+/// cipher key and `Sign` when a request can not be signed with the
+/// secret key. `Grant` is returned when a token grant is not sent or
+/// its response has no token.
 #[derive(Debug)]
 pub enum Error {
     Initialize,
@@ -40,68 +94,440 @@ pub enum Error {
     SubscribeWrite,
     SubscribeRead,
     MissingChannel,
+    InvalidChannel(String),
     HTTPResponse,
+    HTTPStatus(u16),
+    BadRequest(String),
+    AccessDenied(String),
+    PayloadTooLarge,
+    URITooLong,
+    RateLimited,
+    Encrypt,
+    Sign,
+    Grant,
+}
+
+impl Error {
+    /// ## Transient Errors
+    ///
+    /// Network failures, request timeouts (408), rate limits and server
+    /// errors may succeed when retried. Rejected requests fail again until the keys,
+    /// permissions or message are changed.
+    ///
+    /// ```
+    /// use nats_bridge::pubnub::Error;
+    ///
+    /// assert!(Error::RateLimited.is_transient());
+    /// assert!(Error::HTTPStatus(503).is_transient());
+    /// assert!(Error::HTTPStatus(408).is_transient());
+    /// assert!(!Error::AccessDenied("Forbidden".into()).is_transient());
+    /// ```
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::HTTPStatus(status) => *status == 408 || *status >= 500,
+            Error::MissingChannel
+            | Error::InvalidChannel(_)
+            | Error::BadRequest(_)
+            | Error::AccessDenied(_)
+            | Error::PayloadTooLarge
+            | Error::URITooLong
+            | Error::Encrypt
+            | Error::Sign => false,
+            _ => true,
+        }
+    }
+}
+
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+// HTTP or HTTPS Connection
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+fn connect(
+    host: &str,
+    agent: &str,
+    timeout: u64,
+    options: &Options,
+) -> Result<Socket, Error> {
+    match &options.tls {
+        Some(tls) => match Socket::new_tls(host, agent, timeout, tls) {
+            Ok(socket) => Ok(socket),
+            Err(_error) => Err(Error::Initialize),
+        },
+        None => Ok(Socket::new(host, agent, timeout)),
+    }
+}
+
+// Request headers sent with every request
+const HEADERS: &str = "Accept-Encoding: gzip\r\n";
+
+// ROOT.CHANNEL
+fn rooted(root: &str, channel: &str) -> String {
+    if root.is_empty() {
+        channel.to_string()
+    } else {
+        format!("{root}.{channel}", channel = channel, root = root)
+    }
+}
+
+// Channels to subscribe to may be a `,` separated list,
+// each one is below the root and validated on its own
+fn rooted_channels(root: &str, channels: &str) -> Result<String, Error> {
+    let mut rooted_channels = Vec::new();
+    for channel in channels.split(',') {
+        let channel = rooted(root, channel.trim());
+        if !subject::is_valid_channel_pattern(&channel) {
+            return Err(Error::InvalidChannel(channel));
+        }
+        rooted_channels.push(channel);
+    }
+    Ok(rooted_channels.join(","))
+}
+
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+// Access Manager Request Signing
+// With a secret key every request carries a `timestamp` and a v2
+// `signature`, the HMAC-SHA256 of the method, publish key, path,
+// sorted query string and body, base64 URL encoded without padding.
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+
+// Query values are encoded as PubNub encodes them when signing,
+// everything but letters, digits, `-`, `_` and `.`
+const QUERY: &AsciiSet =
+    &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.');
+
+// Query string of the request, signed when there is a secret key
+fn query(
+    method: &str,
+    path: &str,
+    mut params: BTreeMap<&str, String>,
+    body: &str,
+    publish_key: &str,
+    secret_key: &str,
+) -> Result<String, Error> {
+    if secret_key.is_empty() {
+        return Ok(encode_query(&params));
+    }
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_secs(),
+        Err(_error) => return Err(Error::Sign),
+    };
+    params.insert("timestamp", timestamp.to_string());
+    let query = encode_query(&params);
+    let signature =
+        sign(secret_key, method, publish_key, path, &query, body)?;
+    Ok(format!("{}&signature={}", query, signature))
+}
+
+// Sorted by name
+fn encode_query(params: &BTreeMap<&str, String>) -> String {
+    params
+        .iter()
+        .map(|(name, value)| {
+            format!("{}={}", name, utf8_percent_encode(value, QUERY))
+        })
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+// {method}\n{publish_key}\n{path}\n{query}\n{body}
+fn sign(
+    secret_key: &str,
+    method: &str,
+    publish_key: &str,
+    path: &str,
+    query: &str,
+    body: &str,
+) -> Result<String, Error> {
+    let input =
+        format!("{}\n{}\n{}\n{}\n{}", method, publish_key, path, query, body);
+    let digest = PKey::hmac(secret_key.as_bytes()).and_then(|key| {
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(input.as_bytes())?;
+        signer.sign_to_vec()
+    });
+    match digest {
+        Ok(digest) => Ok(format!(
+            "v2.{}",
+            base64::encode_block(&digest)
+                .replace('+', "-")
+                .replace('/', "_")
+                .trim_end_matches('=')
+        )),
+        Err(_error) => Err(Error::Sign),
+    }
+}
+
+// Query values that must not appear in the logs
+const SECRET_PARAMS: [&str; 2] = ["auth=", "signature="];
+
+// Send a request, logging its request line with the auth key or token
+// and the signature masked
+fn send_request(socket: &mut Socket, request: &str) -> Result<(), Error> {
+    let line = request.lines().next().unwrap_or_default();
+    socket.log(&redacted(line));
+    match socket.send(request.as_bytes()) {
+        Ok(_size) => Ok(()),
+        Err(_error) => Err(Error::HTTPResponse),
+    }
+}
+
+fn redacted(line: &str) -> String {
+    let mut redacted = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find(&['?', '&'][..]) {
+        redacted.push_str(&rest[..=start]);
+        rest = &rest[start + 1..];
+        if let Some(name) =
+            SECRET_PARAMS.iter().find(|name| rest.starts_with(*name))
+        {
+            let end = rest.find(&['&', ' '][..]).unwrap_or(rest.len());
+            redacted.push_str(name);
+            redacted.push_str("********");
+            rest = &rest[end..];
+        }
+    }
+    redacted.push_str(rest);
+    redacted
 }
 
 // =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
 // HTTP Response Reader/Parser
+// Status line, headers with lowercase names and a body framed by
+// Content-Length or chunked Transfer-Encoding, then decompressed when
+// it has a Content-Encoding.
+// Bodies over `MAX_BODY`, before or after decompressing, are refused
+// instead of being read into memory.
 // =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+const MAX_BODY: usize = 4 * 1024 * 1024;
+
 fn http_response(socket: &mut Socket) -> Result<JsonValue, Error> {
-    let mut body_length: usize = 0;
+    let response = match read_response(socket) {
+        Ok(response) => response,
+        Err(error) => {
+            // Start over on a new connection rather than pipelining
+            socket.disconnect();
+            return Err(error);
+        }
+    };
+    if response.header("connection") == Some("close") {
+        socket.disconnect();
+    }
+    response_body(&response)
+}
+
+// JSON body of a successful response, or the error for the status
+fn response_body(response: &Response) -> Result<JsonValue, Error> {
+    let body = match std::str::from_utf8(&response.body) {
+        Ok(body) => json::parse(body).ok(),
+        Err(_error) => None,
+    };
+    let body = match (response.status, body) {
+        (200..=299, Some(body)) => return Ok(body),
+        (200..=299, None) => return Err(Error::HTTPResponse),
+        (_, body) => body.unwrap_or(JsonValue::Null),
+    };
+
+    // {"status":403,"error":true,"message":"Forbidden",...}
+    // [0,"Message Too Large","16195023423187265"]
+    let message = body["message"]
+        .as_str()
+        .or_else(|| body[1].as_str())
+        .unwrap_or_default()
+        .to_string();
+    Err(match response.status {
+        400 => Error::BadRequest(message),
+        403 => Error::AccessDenied(message),
+        413 => Error::PayloadTooLarge,
+        414 => Error::URITooLong,
+        429 => Error::RateLimited,
+        status => Error::HTTPStatus(status),
+    })
+}
+
+struct Response {
+    status: u16,
+    headers: BTreeMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+// Line and exact length reads, from a socket or canned test responses
+trait HttpRead {
+    fn readln(&mut self) -> Result<String, socket::Error>;
+    fn read_exact(&mut self, bytes: usize) -> Result<Vec<u8>, socket::Error>;
+}
+
+impl HttpRead for Socket {
+    fn readln(&mut self) -> Result<String, socket::Error> {
+        Socket::readln(self)
+    }
+
+    fn read_exact(&mut self, bytes: usize) -> Result<Vec<u8>, socket::Error> {
+        Socket::read_exact(self, bytes)
+    }
+}
+
+fn read_response(reader: &mut impl HttpRead) -> Result<Response, Error> {
+    // HTTP/1.1 200 OK
+    let status_line = loop {
+        let line = read_line(reader)?;
+        if !line.is_empty() {
+            break line;
+        }
+    };
+    let mut parts = status_line.split_whitespace();
+    let status = match (parts.next(), parts.next().map(str::parse)) {
+        (Some(version), Some(Ok(status))) if version.starts_with("HTTP/") => {
+            status
+        }
+        _ => return Err(Error::HTTPResponse),
+    };
+
+    let headers = read_headers(reader)?;
+    let mut response = Response {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+
+    // Responses without a body
+    if status < 200 || status == 204 || status == 304 {
+        return Ok(response);
+    }
+
+    let chunked = response
+        .header("transfer-encoding")
+        .is_some_and(|encoding| encoding.contains("chunked"));
+    response.body = if chunked {
+        read_chunked(reader)?
+    } else {
+        match response.header("content-length").map(str::parse) {
+            Some(Ok(length)) if length <= MAX_BODY => {
+                read_exact(reader, length)?
+            }
+            _ => return Err(Error::HTTPResponse),
+        }
+    };
+
+    response.body = match response.header("content-encoding") {
+        None | Some("identity") => response.body,
+        Some("gzip") => decode(GzDecoder::new(&response.body[..]))?,
+        Some("deflate") => decode(ZlibDecoder::new(&response.body[..]))?,
+        Some(_) => return Err(Error::HTTPResponse),
+    };
+    Ok(response)
+}
+
+// Header names are case-insensitive, repeated headers are joined
+fn read_headers(
+    reader: &mut impl HttpRead,
+) -> Result<BTreeMap<String, String>, Error> {
+    let mut headers = BTreeMap::new();
     loop {
-        let data = match socket.readln() {
-            Ok(data) => data,
-            Err(_error) => return Err(Error::HTTPResponse),
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => return Err(Error::HTTPResponse),
         };
+        headers
+            .entry(name.to_ascii_lowercase())
+            .and_modify(|values: &mut String| {
+                values.push_str(", ");
+                values.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+}
 
-        // Capture Content Length of Payload
-        if body_length == 0 && data.contains("Content-Length") {
-            let result = match data.split_whitespace().nth(1) {
-                Some(length) => length.parse(),
-                None => return Err(Error::HTTPResponse),
-            };
-            body_length = match result {
-                Ok(length) => length,
-                Err(_error) => return Err(Error::HTTPResponse),
-            };
+// <hex size>[;extension]\r\n<data>\r\n ... 0\r\n[trailers]\r\n
+fn read_chunked(reader: &mut impl HttpRead) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(size) => size,
+            Err(_error) => return Err(Error::HTTPResponse),
+        };
+        if size == 0 {
+            read_headers(reader)?;
+            return Ok(body);
         }
-
-        // End of Headers
-        if data.len() == 2 {
-            let paylaod = match socket.read(body_length) {
-                Ok(data) => data,
-                Err(_error) => return Err(Error::HTTPResponse),
-            };
-            match json::parse(&paylaod) {
-                Ok(response) => return Ok(response),
-                Err(_error) => return Err(Error::HTTPResponse),
-            };
+        if size > MAX_BODY - body.len() {
+            return Err(Error::HTTPResponse);
+        }
+        body.extend(read_exact(reader, size)?);
+        if !read_line(reader)?.is_empty() {
+            return Err(Error::HTTPResponse);
         }
     }
 }
 
+// Line without the CRLF
+fn read_line(reader: &mut impl HttpRead) -> Result<String, Error> {
+    match reader.readln() {
+        Ok(line) => Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string()),
+        Err(_error) => Err(Error::HTTPResponse),
+    }
+}
+
+fn read_exact(
+    reader: &mut impl HttpRead,
+    bytes: usize,
+) -> Result<Vec<u8>, Error> {
+    match reader.read_exact(bytes) {
+        Ok(data) => Ok(data),
+        Err(_error) => Err(Error::HTTPResponse),
+    }
+}
+
+// One byte past the limit tells a body that is too large
+fn decode(decoder: impl Read) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    let limit = MAX_BODY as u64 + 1;
+    match decoder.take(limit).read_to_end(&mut body) {
+        Ok(size) if size <= MAX_BODY => Ok(body),
+        _ => Err(Error::HTTPResponse),
+    }
+}
+
 // =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
 /// # PubNub Subscriber Client
 ///
 /// This client lib offers subscribe support to PubNub.
 ///
 /// ```no_run
-/// use nats_bridge::pubnub::SubscribeClient;
+/// use nats_bridge::pubnub::{Options, SubscribeClient};
+/// use nats_bridge::socket::Tls;
 ///
-/// let host = "psdsn.pubnub.com:80";
+/// let host = "psdsn.pubnub.com:443";
 /// let channel = "demo";
 /// let root = "";
 /// let publish_key = "demo";
 /// let subscribe_key = "demo";
-/// let _secret_key = "secret";
+/// let secret_key = "";
 /// let agent = "nats-bridge";
+/// let options = Options {
+///     tls: Some(Tls::default()),
+///     cipher: None,
+///     auth_key: "".into(),
+/// };
 /// let mut pubnub = SubscribeClient::new(
 ///     host,
 ///     root,
 ///     channel,
+///     publish_key,
 ///     subscribe_key,
-///     _secret_key,
+///     secret_key,
 ///     agent,
+///     &options,
 ///  ).expect("NATS Subscribe Client");
 ///
 /// let result = pubnub.next_message();
@@ -111,15 +537,23 @@ fn http_response(socket: &mut Socket) -> Result<JsonValue, Error> {
 /// ```
 // =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
 impl SubscribeClient {
+    // The keys are taken like by the Publish Client, the publish key
+    // signs subscribes when there is a secret key
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         host: &str,
         root: &str,
         channel: &str,
+        publish_key: &str,
         subscribe_key: &str,
-        _secret_key: &str,
+        secret_key: &str,
         agent: &str,
+        options: &Options,
     ) -> Result<Self, Error> {
-        let socket = Socket::new(host, agent, 30);
+        if !channel.is_empty() {
+            rooted_channels(root, channel)?;
+        }
+        let socket = connect(host, agent, 30, options)?;
 
         let mut pubnub = Self {
             socket,
@@ -127,9 +561,13 @@ impl SubscribeClient {
             channel: channel.into(),
             messages: Vec::new(),
             timetoken: "0".into(),
+            publish_key: publish_key.into(),
             subscribe_key: subscribe_key.into(),
-            _secret_key: _secret_key.into(),
+            secret_key: secret_key.into(),
             agent: agent.into(),
+            auth_key: options.auth_key.clone(),
+            cipher: options.cipher.clone(),
+            undecrypted: false,
         };
 
         match pubnub.subscribe() {
@@ -147,12 +585,15 @@ impl SubscribeClient {
         // Capture
         let response: JsonValue = match http_response(&mut self.socket) {
             Ok(data) => data,
-            Err(_error) => {
+            Err(error) => {
                 // Already returning an error, would you like another?
                 let _ = self.subscribe().is_err();
 
                 // Return first error
-                return Err(Error::SubscribeRead);
+                return match error {
+                    Error::HTTPResponse => Err(Error::SubscribeRead),
+                    error => Err(error),
+                };
             }
         };
 
@@ -171,10 +612,11 @@ impl SubscribeClient {
                 source[self.root.len() + 1..].to_string()
             };
 
+            let data = self.decrypt(&message["d"]);
             self.messages.push(Message {
                 root: self.root.to_string(),
                 channel,
-                data: message["d"].to_string(),
+                data,
                 metadata: meta,
                 id: message["p"]["t"].to_string(),
             });
@@ -187,33 +629,65 @@ impl SubscribeClient {
         }
     }
 
+    // Encrypted messages arrive as a base64 string of the JSON message.
+    // Messages that do not decrypt, like from publishers without the
+    // cipher key, are passed on as they are.
+    // Only the first one is logged, a channel shared with such publishers
+    // would otherwise log every message.
+    fn decrypt(&mut self, data: &JsonValue) -> String {
+        let (cipher, encrypted) = match (&self.cipher, data.as_str()) {
+            (Some(cipher), Some(encrypted)) => (cipher, encrypted),
+            _ => return data.to_string(),
+        };
+        match cipher.decrypt(encrypted).map(|text| json::parse(&text)) {
+            Ok(Ok(message)) => message.to_string(),
+            _ => {
+                if !self.undecrypted {
+                    socket::log(
+                        self.socket.host(),
+                        "PubNub Subscriber",
+                        "Message not decrypted with the cipher key, \
+                         passing on undecrypted messages without logging",
+                    );
+                    self.undecrypted = true;
+                }
+                data.to_string()
+            }
+        }
+    }
+
     fn subscribe(&mut self) -> Result<(), Error> {
         // Don't subscribe if without a channel
         if self.channel.is_empty() {
             return Err(Error::MissingChannel);
         }
-        let channel = if self.root.is_empty() {
-            self.channel.to_string()
-        } else {
-            format!(
-                "{root}.{channel}",
-                channel = self.channel,
-                root = self.root
-            )
-        };
-        let uri = format!(
-            "/v2/subscribe/{subscribe_key}/{channel}/0/{timetoken}?pnsdk={agent}&filter-expr={filter}",
-            //"/v2/subscribe/{subscribe_key}/{channel}/0/{timetoken}?pnsdk={agent}",
+        let channel = rooted_channels(&self.root, &self.channel)?;
+        let path = format!(
+            "/v2/subscribe/{subscribe_key}/{channel}/0/{timetoken}",
             subscribe_key = self.subscribe_key,
             channel = channel,
             timetoken = self.timetoken,
-            agent = self.agent,
-            filter = "source%22%21%3D%22%27NATS%27".to_string(),
         );
-        let request =
-            format!("GET {} HTTP/1.1\r\nHost: pubnub\r\n\r\n", uri,);
-        match self.socket.write(request) {
-            Ok(_size) => Ok(()),
+        let mut params = BTreeMap::new();
+        params.insert("pnsdk", self.agent.clone());
+        params.insert("filter-expr", "source\"!=\"'NATS'".into());
+        if !self.auth_key.is_empty() {
+            params.insert("auth", self.auth_key.clone());
+        }
+        let query = query(
+            "GET",
+            &path,
+            params,
+            "",
+            &self.publish_key,
+            &self.secret_key,
+        )?;
+        let request = format!(
+            "GET {}?{} HTTP/1.1\r\nHost: pubnub\r\n{}\r\n",
+            path, query, HEADERS
+        );
+        match send_request(&mut self.socket, &request) {
+            Ok(()) => Ok(()),
             Err(_error) => Err(Error::SubscribeWrite),
         }
     }
@@ -225,22 +699,29 @@ impl SubscribeClient {
 /// This client lib offers publish support to PubNub.
 ///
 /// ```no_run
-/// use nats_bridge::pubnub::PublishClient;
+/// use nats_bridge::pubnub::{Options, PublishClient};
+/// use nats_bridge::socket::Tls;
 ///
-/// let host = "psdsn.pubnub.com:80";
+/// let host = "psdsn.pubnub.com:443";
 /// let root = "";
 /// let channel = "demo";
 /// let publish_key = "demo";
 /// let subscribe_key = "demo";
-/// let _secret_key = "secret";
+/// let secret_key = "";
 /// let agent = "nats-bridge";
+/// let options = Options {
+///     tls: Some(Tls::default()),
+///     cipher: None,
+///     auth_key: "".into(),
+/// };
 /// let mut pubnub = PublishClient::new(
 ///     host,
 ///     root,
 ///     publish_key,
 ///     subscribe_key,
-///     _secret_key,
+///     secret_key,
 ///     agent,
+///     &options,
 ///  ).expect("NATS Subscribe Client");
 ///
 /// let result = pubnub.publish(channel, "data");
@@ -255,16 +736,23 @@ impl PublishClient {
         subscribe_key: &str,
         secret_key: &str,
         agent: &str,
+        options: &Options,
     ) -> Result<Self, Error> {
-        let socket = Socket::new(host, agent, 5);
+        // PubNub would refuse every publish
+        if publish_key.is_empty() {
+            return Err(Error::BadRequest("Missing publish key".into()));
+        }
+        let socket = connect(host, agent, 5, options)?;
 
         Ok(Self {
             socket,
             root: root.into(),
             publish_key: publish_key.into(),
             subscribe_key: subscribe_key.into(),
-            _secret_key: secret_key.into(),
+            secret_key: secret_key.into(),
             agent: agent.into(),
+            auth_key: options.auth_key.clone(),
+            cipher: options.cipher.clone(),
         })
     }
 
@@ -273,35 +761,924 @@ impl PublishClient {
         channel: &str,
         message: &str,
     ) -> Result<String, Error> {
-        let encoded_message =
-            utf8_percent_encode(message, NON_ALPHANUMERIC).to_string();
-        let channel = if self.root.is_empty() {
-            channel.to_string()
-        } else {
-            format!("{root}.{channel}", channel = channel, root = self.root)
+        self.publish_with_meta(channel, message, &JsonValue::new_object())
+    }
+
+    /// ## Publish with Metadata
+    ///
+    /// Attach a JSON object as the message `meta`.
+    /// Subscribers receive it alongside the message and can filter on it.
+    /// The `source` field is always set to `NATS`.
+    ///
+    /// ```no_run
+    /// use nats_bridge::pubnub::{Options, PublishClient};
+    ///
+    /// let mut pubnub = PublishClient::new(
+    ///     "psdsn.pubnub.com:80",
+    ///     "",
+    ///     "demo",
+    ///     "demo",
+    ///     "secret",
+    ///     "nats-bridge",
+    ///     &Options::default(),
+    /// )
+    /// .expect("PubNub Publish Client");
+    ///
+    /// let meta = json::object! { "reply_to" => "_INBOX.2bOSjvkI7uBN5gBlCqHfJp" };
+    /// pubnub
+    ///     .publish_with_meta("demo", "\"Hello\"", &meta)
+    ///     .expect("Published");
+    /// ```
+    pub fn publish_with_meta(
+        &mut self,
+        channel: &str,
+        message: &str,
+        meta: &JsonValue,
+    ) -> Result<String, Error> {
+        let message = match &self.cipher {
+            Some(cipher) => match cipher.encrypt(message) {
+                Ok(encrypted) => json::stringify(encrypted),
+                Err(_error) => return Err(Error::Encrypt),
+            },
+            None => message.to_string(),
         };
-        let uri = format!(
-            "/publish/{}/{}/0/{}/0/{}?pnsdk={pnsdk}&meta={meta}",
-            self.publish_key,
-            self.subscribe_key,
-            channel,
-            encoded_message,
-            pnsdk=self.agent,
-            meta="{\"source\":\"NATS\"}"
+        let encoded_message =
+            utf8_percent_encode(&message, NON_ALPHANUMERIC).to_string();
+        let mut meta = meta.clone();
+        meta["source"] = "NATS".into();
+        let channel = rooted(&self.root, channel);
+        if !subject::is_valid_channel(&channel) {
+            return Err(Error::InvalidChannel(channel));
+        }
+        let path = format!(
+            "/publish/{}/{}/0/{}/0/{}",
+            self.publish_key, self.subscribe_key, channel, encoded_message,
         );
+        let mut params = BTreeMap::new();
+        params.insert("pnsdk", self.agent.clone());
+        params.insert("meta", json::stringify(meta));
+        if !self.auth_key.is_empty() {
+            params.insert("auth", self.auth_key.clone());
+        }
+        let query = query(
+            "GET",
+            &path,
+            params,
+            "",
+            &self.publish_key,
+            &self.secret_key,
+        )?;
 
-        let request =
-            format!("GET {} HTTP/1.1\r\nHost: pubnub\r\n\r\n", uri,);
-        let _size = match self.socket.write(request) {
-            Ok(size) => size,
-            Err(_error) => return Err(Error::PublishWrite),
-        };
+        let request = format!(
+            "GET {}?{} HTTP/1.1\r\nHost: pubnub\r\n{}\r\n",
+            path, query, HEADERS
+        );
+        if send_request(&mut self.socket, &request).is_err() {
+            return Err(Error::PublishWrite);
+        }
 
         // Capture and return TimeToken
         let response: JsonValue = match http_response(&mut self.socket) {
             Ok(data) => data,
-            Err(_error) => return Err(Error::PublishResponse),
+            Err(Error::HTTPResponse) => return Err(Error::PublishResponse),
+            Err(error) => return Err(error),
         };
         Ok(response[2].to_string())
     }
+
+    /// ## Grant Access Manager Token
+    ///
+    /// Mint a token with the secret key, for this bridge or for devices.
+    /// Channels are below the channel root like on publish, and may end
+    /// with a `.*` wildcard to grant every channel below the prefix.
+    /// The token is sent by clients as their auth key.
+    ///
+    /// ```no_run
+    /// use nats_bridge::pubnub::{Grant, Options, PublishClient};
+    ///
+    /// let mut pubnub = PublishClient::new(
+    ///     "psdsn.pubnub.com:80",
+    ///     "channels",
+    ///     "demo",
+    ///     "demo",
+    ///     "secret",
+    ///     "nats-bridge",
+    ///     &Options::default(),
+    /// )
+    /// .expect("PubNub Publish Client");
+    ///
+    /// let grant = Grant {
+    ///     ttl: 60,
+    ///     authorized_uuid: "device-1".into(),
+    ///     channels: vec!["devices.device-1".into(), "orders.*".into()],
+    ///     read: true,
+    ///     write: true,
+    /// };
+    /// let token = pubnub.grant_token(&grant).expect("Granted");
+    /// println!("{}", token);
+    /// ```
+    pub fn grant_token(&mut self, grant: &Grant) -> Result<String, Error> {
+        if self.secret_key.is_empty() {
+            return Err(Error::Sign);
+        }
+        let body = json::stringify(grant_body(&self.root, grant)?);
+        let path = format!("/v3/pam/{}/grant", self.subscribe_key);
+        let mut params = BTreeMap::new();
+        params.insert("pnsdk", self.agent.clone());
+        let query = query(
+            "POST",
+            &path,
+            params,
+            &body,
+            &self.publish_key,
+            &self.secret_key,
+        )?;
+
+        let request = format!(
+            "POST {}?{} HTTP/1.1\r\nHost: pubnub\r\n{}\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            path,
+            query,
+            HEADERS,
+            body.len(),
+            body
+        );
+        if send_request(&mut self.socket, &request).is_err() {
+            return Err(Error::Grant);
+        }
+
+        // {"status":200,"data":{"message":"Success","token":"..."},...}
+        let response: JsonValue = match http_response(&mut self.socket) {
+            Ok(data) => data,
+            Err(Error::HTTPResponse) => return Err(Error::Grant),
+            Err(error) => return Err(error),
+        };
+        match response["data"]["token"].as_str() {
+            Some(token) => Ok(token.to_string()),
+            None => Err(Error::Grant),
+        }
+    }
+}
+
+/// # Access Manager Grant
+///
+/// Permissions of a token for `ttl` minutes, used by the client
+/// with the `authorized_uuid`. Every channel gets the same permissions.
+/// The `ttl` must be at least one minute, a grant without one is not sent.
+#[derive(Clone, Debug, Default)]
+pub struct Grant {
+    pub ttl: u32,
+    pub authorized_uuid: String,
+    pub channels: Vec<String>,
+    pub read: bool,
+    pub write: bool,
+}
+
+// Permission bits of Access Manager v3
+const READ: u32 = 1;
+const WRITE: u32 = 2;
+
+// Channels are granted by name, wildcards as a regular expression
+// pattern matching the channels below the prefix
+// Patterns match every channel below the prefix, however deep, unlike
+// subscribe wildcards
+fn grant_body(root: &str, grant: &Grant) -> Result<JsonValue, Error> {
+    if grant.ttl == 0 {
+        return Err(Error::Grant);
+    }
+    let permissions =
+        u32::from(grant.read) * READ + u32::from(grant.write) * WRITE;
+    let mut channels = JsonValue::new_object();
+    let mut patterns = JsonValue::new_object();
+    for channel in &grant.channels {
+        let channel = rooted(root, channel);
+        match channel.strip_suffix(".*") {
+            Some(prefix) if subject::is_valid_channel(prefix) => {
+                let pattern = format!("^{}\\.", escape_pattern(prefix));
+                patterns[pattern] = permissions.into();
+            }
+            None if subject::is_valid_channel(&channel) => {
+                channels[channel] = permissions.into();
+            }
+            _ => return Err(Error::InvalidChannel(channel)),
+        }
+    }
+
+    let mut body = json::object! {
+        "ttl" => grant.ttl,
+        "permissions" => json::object! {
+            "resources" => json::object! {
+                "channels" => channels,
+                "groups" => JsonValue::new_object(),
+                "uuids" => JsonValue::new_object(),
+            },
+            "patterns" => json::object! {
+                "channels" => patterns,
+                "groups" => JsonValue::new_object(),
+                "uuids" => JsonValue::new_object(),
+            },
+            "meta" => JsonValue::new_object(),
+        },
+    };
+    if !grant.authorized_uuid.is_empty() {
+        body["permissions"]["uuid"] = grant.authorized_uuid.clone().into();
+    }
+    Ok(body)
+}
+
+fn escape_pattern(text: &str) -> String {
+    let mut escaped = String::new();
+    for character in text.chars() {
+        if "\\^$.|?*+()[]{}".contains(character) {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nats::tests::certificates;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use openssl::ssl::SslAcceptor;
+    use std::io::{BufRead, BufReader, Cursor, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Canned response data with the same reads as a socket
+    struct Canned(Cursor<Vec<u8>>);
+
+    impl HttpRead for Canned {
+        fn readln(&mut self) -> Result<String, socket::Error> {
+            let mut line = String::new();
+            match self.0.read_line(&mut line) {
+                Ok(0) | Err(_) => Err(socket::Error::Read),
+                Ok(_size) => Ok(line),
+            }
+        }
+
+        fn read_exact(
+            &mut self,
+            bytes: usize,
+        ) -> Result<Vec<u8>, socket::Error> {
+            let mut buffer = vec![0u8; bytes];
+            match Read::read_exact(&mut self.0, &mut buffer) {
+                Ok(()) => Ok(buffer),
+                Err(_error) => Err(socket::Error::Read),
+            }
+        }
+    }
+
+    // The signatures below are not vendor test vectors, none were at hand.
+    // They were computed apart from this code with Python's `hmac` and
+    // `hashlib.sha256`, URL-safe base64 without padding, over the string
+    // documented for PubNub Access Manager v2 signatures:
+    // {method}\n{publish_key}\n{path}\n{query}\n{body}
+    // PubNub SDKs sign the query as they send it, encoded like the
+    // JavaScript SDK `encodeString`: `encodeURIComponent`, then `!~*'()`
+    // escaped too, so only letters, digits, `-`, `_` and `.` are kept.
+    // UTF-8 is encoded per byte, so `£` is `%C2%A3`, and space is `%20`.
+    #[test]
+    fn encode_query_reserved() {
+        let mut params = BTreeMap::new();
+        params.insert("PoundsSterling", "£13.37".to_string());
+        params.insert("reserved", "!~*'() +:/?#[]@$&,;=".into());
+        params.insert("unreserved", "AZaz09-_.".into());
+        assert_eq!(
+            encode_query(&params),
+            "PoundsSterling=%C2%A313.37\
+             &reserved=%21%7E%2A%27%28%29%20%2B%3A%2F%3F%23%5B%5D%40%24%26%2C%3B%3D\
+             &unreserved=AZaz09-_."
+        );
+    }
+
+    #[test]
+    fn sign_subscribe_ok() {
+        let path = "/v2/subscribe/demo/demo/0/0";
+        let mut params = BTreeMap::new();
+        params.insert("pnsdk", "nats-bridge".to_string());
+        params.insert("filter-expr", "source\"!=\"'NATS'".into());
+        params.insert("timestamp", "1618333333".into());
+        let query = encode_query(&params);
+        assert_eq!(
+            query,
+            "filter-expr=source%22%21%3D%22%27NATS%27\
+             &pnsdk=nats-bridge&timestamp=1618333333"
+        );
+        let signature = sign(
+            "wMfbo9G0xVUG8yfTfYw5qIdfJkTd7A",
+            "GET",
+            "demo",
+            path,
+            &query,
+            "",
+        )
+        .expect("Signed");
+        assert_eq!(
+            signature,
+            "v2.cemBEn_7pVAKzpUFU8I3HCGYg4pVzm0P9MZGwcb2ulw"
+        );
+    }
+
+    #[test]
+    fn sign_publish_ok() {
+        let path = "/publish/pub-c-demo/sub-c-demo/0/channels.orders/0/\
+                    %22Hello%22";
+        let mut params = BTreeMap::new();
+        params.insert("pnsdk", "nats-bridge".to_string());
+        params.insert("meta", "{\"source\":\"NATS\"}".into());
+        params.insert("timestamp", "1700000000".into());
+        let query = encode_query(&params);
+        assert_eq!(
+            query,
+            "meta=%7B%22source%22%3A%22NATS%22%7D\
+             &pnsdk=nats-bridge&timestamp=1700000000"
+        );
+        let signature =
+            sign("sec-c-secret", "GET", "pub-c-demo", path, &query, "")
+                .expect("Signed");
+        assert_eq!(
+            signature,
+            "v2.wSf1HO6YQC6kOrJ7p2Cez-edWIkWLBNx5MtJ_nPz4E4"
+        );
+    }
+
+    #[test]
+    fn grant_body_ok() {
+        let grant = Grant {
+            ttl: 60,
+            authorized_uuid: "device-1".into(),
+            channels: vec!["devices.device-1".into(), "orders.*".into()],
+            read: true,
+            write: false,
+        };
+        let body = grant_body("channels", &grant).expect("Body");
+        let permissions = &body["permissions"];
+        assert_eq!(body["ttl"], 60);
+        assert_eq!(permissions["uuid"], "device-1");
+        assert_eq!(
+            permissions["resources"]["channels"]["channels.devices.device-1"],
+            1
+        );
+        assert_eq!(
+            permissions["patterns"]["channels"]["^channels\\.orders\\."],
+            1
+        );
+
+        // Deeper prefixes than a subscribe allows
+        let grant = Grant {
+            channels: vec!["a.b.c.*".into()],
+            ..grant
+        };
+        let body = grant_body("channels", &grant).expect("Body");
+        assert_eq!(
+            body["permissions"]["patterns"]["channels"]
+                ["^channels\\.a\\.b\\.c\\."],
+            1
+        );
+
+        for channel in ["*", ".*", "a.*.b", "a.*.*", "a*", "a b.*", "a."] {
+            let grant = Grant {
+                channels: vec![channel.into()],
+                ..grant.clone()
+            };
+            match grant_body("", &grant) {
+                Err(Error::InvalidChannel(invalid)) => {
+                    assert_eq!(invalid, channel)
+                }
+                _ => panic!("Expected an invalid channel {:?}", channel),
+            }
+        }
+
+        let grant = Grant { ttl: 0, ..grant };
+        assert!(matches!(grant_body("", &grant), Err(Error::Grant)));
+    }
+
+    #[test]
+    fn sign_grant_ok() {
+        let body =
+            "{\"ttl\":60,\"permissions\":{\"resources\":{\"channels\":\
+                    {\"channels.devices.device-1\":1},\"groups\":{},\
+                    \"uuids\":{}},\"patterns\":{\"channels\":\
+                    {\"^channels\\\\.orders\\\\.\":1},\"groups\":{},\
+                    \"uuids\":{}},\"meta\":{},\"uuid\":\"device-1\"}}";
+        let signature = sign(
+            "sec-c-secret",
+            "POST",
+            "pub-c-demo",
+            "/v3/pam/sub-c-demo/grant",
+            "pnsdk=nats-bridge&timestamp=1700000000",
+            body,
+        )
+        .expect("Signed");
+        assert_eq!(
+            signature,
+            "v2.CJA-5tAExDo0SGTbEVmtx_AdN9ZF8sTfoaH9ELZjecY"
+        );
+    }
+
+    #[test]
+    fn publish_key_missing() {
+        let result = PublishClient::new(
+            "127.0.0.1:1",
+            "",
+            "",
+            "demo",
+            "",
+            "",
+            &Options::default(),
+        );
+        assert!(matches!(result, Err(Error::BadRequest(_))));
+    }
+
+    #[test]
+    fn rooted_channels_ok() {
+        let channels = rooted_channels("root", "a, b.*,c.d").expect("List");
+        assert_eq!(channels, "root.a,root.b.*,root.c.d");
+
+        match rooted_channels("root", "a,,b") {
+            Err(Error::InvalidChannel(channel)) => {
+                assert_eq!(channel, "root.")
+            }
+            _ => panic!("Expected an invalid channel"),
+        }
+        match rooted_channels("root", "a,b:c") {
+            Err(Error::InvalidChannel(channel)) => {
+                assert_eq!(channel, "root.b:c")
+            }
+            _ => panic!("Expected an invalid channel"),
+        }
+    }
+
+    #[test]
+    fn redacted_ok() {
+        let line = "GET /v2/subscribe/demo/demo/0/0?auth=p0F2AkF0Gm\
+                    &pnsdk=nats-bridge&timestamp=1700000000\
+                    &signature=v2.wSf1HO6Y HTTP/1.1";
+        assert_eq!(
+            redacted(line),
+            "GET /v2/subscribe/demo/demo/0/0?auth=********\
+             &pnsdk=nats-bridge&timestamp=1700000000\
+             &signature=******** HTTP/1.1"
+        );
+
+        // Only whole parameter names are masked
+        let line = "GET /publish/a/b/0/c/0/1?meta=oauth%3D1 HTTP/1.1";
+        assert_eq!(redacted(line), line);
+    }
+
+    #[test]
+    fn query_unsigned_ok() {
+        let mut params = BTreeMap::new();
+        params.insert("pnsdk", "nats-bridge".to_string());
+        let query =
+            query("GET", "/time/0", params, "", "demo", "").expect("Query");
+        assert_eq!(query, "pnsdk=nats-bridge");
+    }
+
+    fn parse(data: &[u8]) -> Result<Response, Error> {
+        read_response(&mut Canned(Cursor::new(data.to_vec())))
+    }
+
+    #[test]
+    fn content_length_ok() {
+        let response = parse(
+            b"HTTP/1.1 200 OK\r\n\
+              Date: Thu, 01 Jan 2026 00:00:00 GMT\r\n\
+              Content-Type: text/javascript; charset=\"UTF-8\"\r\n\
+              Content-Length: 30\r\n\
+              \r\n\
+              [1,\"Sent\",\"16195023423187265\"]",
+        )
+        .expect("Response");
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/javascript; charset=\"UTF-8\"")
+        );
+        assert_eq!(response.body, b"[1,\"Sent\",\"16195023423187265\"]");
+    }
+
+    #[test]
+    fn headers_case_insensitive() {
+        let response = parse(
+            b"HTTP/1.1 200 OK\r\n\
+              content-LENGTH: 2\r\n\
+              Vary: Accept\r\n\
+              VARY: Origin\r\n\
+              \r\n\
+              {}",
+        )
+        .expect("Response");
+        assert_eq!(response.header("vary"), Some("Accept, Origin"));
+        assert_eq!(response.body, b"{}");
+    }
+
+    #[test]
+    fn chunked_ok() {
+        let response = parse(
+            b"HTTP/1.1 200 OK\r\n\
+              Transfer-Encoding: chunked\r\n\
+              \r\n\
+              7\r\n\
+              {\"t\":{}\r\n\
+              a;name=value\r\n\
+              ,\"m\":[]}\r\n\r\n\
+              0\r\n\
+              Expires: 0\r\n\
+              \r\n",
+        )
+        .expect("Response");
+        assert_eq!(response.body, b"{\"t\":{},\"m\":[]}\r\n");
+    }
+
+    #[test]
+    fn chunked_framing_error() {
+        let result = parse(
+            b"HTTP/1.1 200 OK\r\n\
+              Transfer-Encoding: chunked\r\n\
+              \r\n\
+              2\r\n\
+              {}}\r\n\
+              0\r\n\r\n",
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn gzip_ok() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(b"{\"t\":{\"t\":\"1\"}}")
+            .expect("Written");
+        let body = encoder.finish().expect("Compressed");
+
+        let mut data = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Encoding: gzip\r\n\
+             Content-Length: {}\r\n\
+             \r\n",
+            body.len()
+        )
+        .into_bytes();
+        data.extend_from_slice(&body);
+
+        let response = parse(&data).expect("Response");
+        assert_eq!(response.body, b"{\"t\":{\"t\":\"1\"}}");
+    }
+
+    #[test]
+    fn body_too_large_error() {
+        // Refused even with the whole body at hand
+        let large = vec![b' '; MAX_BODY + 1];
+        let mut data = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+            large.len()
+        )
+        .into_bytes();
+        data.extend_from_slice(&large);
+        assert!(parse(&data).is_err());
+
+        let mut data = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Transfer-Encoding: chunked\r\n\
+             \r\n\
+             2\r\n[]\r\n\
+             {:x}\r\n",
+            MAX_BODY - 1
+        )
+        .into_bytes();
+        data.extend_from_slice(&large[2..]);
+        data.extend_from_slice(b"\r\n0\r\n\r\n");
+        assert!(parse(&data).is_err());
+
+        // Small on the wire, too large once decompressed
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&vec![b' '; MAX_BODY + 1])
+            .expect("Written");
+        let body = encoder.finish().expect("Compressed");
+        let mut data = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Encoding: gzip\r\n\
+             Content-Length: {}\r\n\
+             \r\n",
+            body.len()
+        )
+        .into_bytes();
+        data.extend_from_slice(&body);
+        assert!(body.len() < MAX_BODY);
+        assert!(parse(&data).is_err());
+    }
+
+    #[test]
+    fn status_ok() {
+        let response = parse(
+            b"HTTP/1.1 403 Forbidden\r\n\
+              Content-Length: 2\r\n\
+              \r\n\
+              {}",
+        )
+        .expect("Response");
+        assert_eq!(response.status, 403);
+
+        let response =
+            parse(b"HTTP/1.1 204 No Content\r\n\r\n").expect("Response");
+        assert_eq!(response.status, 204);
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn status_errors() {
+        let error =
+            |data: &[u8]| response_body(&parse(data).expect("Response"));
+        match error(
+            b"HTTP/1.1 403 Forbidden\r\n\
+              Content-Length: 108\r\n\
+              \r\n\
+              {\"message\":\"Forbidden\",\"payload\":{\"channels\":[\"demo\"]},\
+              \"error\":true,\"service\":\"Access Manager\",\"status\":403}",
+        ) {
+            Err(Error::AccessDenied(message)) => assert_eq!(message, "Forbidden"),
+            _ => panic!("Expected access denied"),
+        }
+        match error(
+            b"HTTP/1.1 400 Bad Request\r\n\
+              Content-Length: 37\r\n\
+              \r\n\
+              [0,\"Invalid Key\",\"16195023423187265\"]",
+        ) {
+            Err(Error::BadRequest(message)) => {
+                assert_eq!(message, "Invalid Key")
+            }
+            _ => panic!("Expected a bad request"),
+        }
+        match error(
+            b"HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n",
+        ) {
+            Err(error @ Error::PayloadTooLarge) => {
+                assert!(!error.is_transient())
+            }
+            _ => panic!("Expected payload too large"),
+        }
+        match error(
+            b"HTTP/1.1 414 URI Too Long\r\nContent-Length: 5\r\n\r\n<html",
+        ) {
+            Err(Error::URITooLong) => {}
+            _ => panic!("Expected URI too long"),
+        }
+        match error(
+            b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\r\n",
+        ) {
+            Err(error @ Error::RateLimited) => assert!(error.is_transient()),
+            _ => panic!("Expected rate limited"),
+        }
+        match error(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")
+        {
+            Err(error @ Error::HTTPStatus(502)) => {
+                assert!(error.is_transient())
+            }
+            _ => panic!("Expected bad gateway"),
+        }
+        match error(
+            b"HTTP/1.1 408 Request Timeout\r\nContent-Length: 0\r\n\r\n",
+        ) {
+            Err(error @ Error::HTTPStatus(408)) => {
+                assert!(error.is_transient())
+            }
+            _ => panic!("Expected request timeout"),
+        }
+        match error(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n") {
+            Err(error @ Error::HTTPStatus(404)) => {
+                assert!(!error.is_transient())
+            }
+            _ => panic!("Expected not found"),
+        }
+    }
+
+    #[test]
+    fn short_body_error() {
+        let result = parse(
+            b"HTTP/1.1 200 OK\r\n\
+              Content-Length: 10\r\n\
+              \r\n\
+              {}",
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn status_line_error() {
+        assert!(parse(b"HTTP/1.1 OK\r\n\r\n").is_err());
+        assert!(parse(b"SSH-2.0-OpenSSH\r\n\r\n").is_err());
+        assert!(parse(b"HTTP/1.1 200 OK\r\nBroken\r\n\r\n").is_err());
+    }
+
+    // HTTP server answering one request per response
+    // Returns the request lines received
+    fn http_mock(
+        host: &str,
+        responses: Vec<String>,
+    ) -> thread::JoinHandle<Vec<String>> {
+        let listener = TcpListener::bind(host).expect("Unable to listen");
+        thread::spawn(move || {
+            let (socket, _addr) = listener.accept().expect("Client");
+            let mut reader = BufReader::new(socket);
+            let mut requests = Vec::new();
+            for body in responses {
+                let mut line = String::new();
+                reader.read_line(&mut line).expect("Request line");
+                requests.push(line.clone());
+                while line != "\r\n" {
+                    line.clear();
+                    reader.read_line(&mut line).expect("Header");
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                reader
+                    .get_mut()
+                    .write_all(response.as_bytes())
+                    .expect("Response");
+            }
+            requests
+        })
+    }
+
+    #[test]
+    fn publish_encrypted_ok() {
+        let host = "127.0.0.1:4262";
+        let published = "[1,\"Sent\",\"16000000000000000\"]";
+        let t = http_mock(host, vec![published.into()]);
+
+        let cipher = Cipher::new("enigma", true).expect("Cipher");
+        let options = Options {
+            cipher: Some(cipher.clone()),
+            ..Options::default()
+        };
+        let mut pubnub = PublishClient::new(
+            host, "root", "demo", "demo", "", "", &options,
+        )
+        .expect("PubNub Publish Client");
+        pubnub
+            .publish("demo", "{\"text\":\"hello\"}")
+            .expect("Published");
+
+        // The message is the JSON string of the encrypted message
+        let requests = t.join().expect("Thread died early...");
+        let prefix = "GET /publish/demo/demo/0/root.demo/0/";
+        let path = requests[0].strip_prefix(prefix).expect("Publish path");
+        let encoded = &path[..path.find('?').expect("Query")];
+        let message = percent_encoding::percent_decode_str(encoded)
+            .decode_utf8()
+            .expect("UTF-8");
+        let encrypted = json::parse(&message).expect("JSON string");
+        assert!(encrypted.is_string());
+        let decrypted = cipher
+            .decrypt(encrypted.as_str().expect("Encrypted"))
+            .expect("Decrypted");
+        assert_eq!(decrypted, "{\"text\":\"hello\"}");
+    }
+
+    #[test]
+    fn subscribe_decrypted_ok() {
+        let host = "127.0.0.1:4263";
+        let cipher = Cipher::new("enigma", true).expect("Cipher");
+        let encrypted =
+            cipher.encrypt("{\"text\":\"hello\"}").expect("Encrypted");
+        let messages = json::object! {
+            "t" => json::object! { "t" => "16000000000000001", "r" => 1 },
+            "m" => json::array![
+                json::object! {
+                    "c" => "root.demo",
+                    "d" => "not encrypted",
+                    "p" => json::object! { "t" => "16000000000000001" },
+                },
+                json::object! {
+                    "c" => "root.demo",
+                    "d" => encrypted.as_str(),
+                    "p" => json::object! { "t" => "16000000000000000" },
+                },
+            ],
+        };
+        let empty = "{\"t\":{\"t\":\"16000000000000001\",\"r\":1},\"m\":[]}";
+        let t =
+            http_mock(host, vec![json::stringify(messages), empty.into()]);
+
+        let options = Options {
+            cipher: Some(cipher),
+            ..Options::default()
+        };
+        let mut pubnub = SubscribeClient::new(
+            host, "root", "demo", "demo", "demo", "", "", &options,
+        )
+        .expect("PubNub Subscribe Client");
+
+        let message = pubnub.next_message().expect("Decrypted message");
+        assert_eq!(message.channel, "demo");
+        assert_eq!(message.data, "{\"text\":\"hello\"}");
+
+        // Passed on as is and logged once
+        let message = pubnub.next_message().expect("Undecrypted message");
+        assert_eq!(message.data, "not encrypted");
+        assert!(pubnub.undecrypted);
+
+        let requests = t.join().expect("Thread died early...");
+        assert!(requests[0].starts_with("GET /v2/subscribe/demo/root.demo/"));
+        assert!(requests[1].contains("/0/16000000000000001?"));
+    }
+
+    // HTTPS server answering one publish request
+    // Returns the request line, none when the handshake failed
+    fn https_mock(
+        host: &str,
+        acceptor: SslAcceptor,
+    ) -> thread::JoinHandle<Option<String>> {
+        let listener = TcpListener::bind(host).expect("Unable to listen");
+        thread::spawn(move || {
+            let (socket, _addr) = listener.accept().expect("Client");
+            let stream = match acceptor.accept(socket) {
+                Ok(stream) => stream,
+                Err(_error) => return None,
+            };
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).expect("Request line");
+            let request = line.clone();
+            while line != "\r\n" {
+                line.clear();
+                reader.read_line(&mut line).expect("Header");
+            }
+            let body = "[1,\"Sent\",\"16000000000000000\"]";
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            reader
+                .get_mut()
+                .write_all(response.as_bytes())
+                .expect("Response");
+            Some(request)
+        })
+    }
+
+    #[test]
+    fn tls_ok() {
+        let host = "127.0.0.1:4260";
+        let certificates = certificates("pubnub-tls-ok");
+        let t = https_mock(host, certificates.acceptor.clone());
+
+        let options = Options {
+            tls: Some(Tls {
+                ca_file: certificates.ca_file,
+                cert_file: certificates.cert_file,
+                key_file: certificates.key_file,
+                server_name: "localhost".into(),
+            }),
+            ..Options::default()
+        };
+        let mut pubnub = PublishClient::new(
+            host, "root", "demo", "demo", "", "", &options,
+        )
+        .expect("PubNub over TLS");
+        let timetoken = pubnub.publish("demo", "1").expect("Published");
+        assert_eq!(timetoken, "16000000000000000");
+
+        let request = t.join().expect("Thread died early...");
+        let request = request.expect("Request over TLS");
+        assert!(request.starts_with("GET /publish/demo/demo/0/root.demo/0/"));
+    }
+
+    #[test]
+    fn tls_untrusted_certificate() {
+        let host = "127.0.0.1:4261";
+        let server = certificates("pubnub-tls-untrusted");
+        let t = https_mock(host, server.acceptor);
+
+        // Signed by a CA the client does not trust
+        let other = certificates("pubnub-tls-other");
+        let options = Options {
+            tls: Some(Tls {
+                ca_file: other.ca_file,
+                cert_file: other.cert_file,
+                key_file: other.key_file,
+                server_name: "localhost".into(),
+            }),
+            ..Options::default()
+        };
+        let result = PublishClient::new(
+            host, "root", "demo", "demo", "", "", &options,
+        );
+        assert!(matches!(result, Err(Error::Initialize)));
+
+        let request = t.join().expect("Thread died early...");
+        assert!(request.is_none());
+    }
 }
@@ -1,20 +1,91 @@
-use std::io::{BufRead, BufReader, Read, Write};
-use std::net::{Shutdown, TcpStream};
+use openssl::rand::rand_bytes;
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslStream};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
 use std::{thread, time};
 
 #[derive(Debug)]
 pub enum Error {
     Write,
     Read,
+    Timeout,
+    Tls,
+}
+
+/// # TLS Settings
+///
+/// Leave a field empty to use the default.
+/// Without a `ca_file` the system trust store verifies the server, and
+/// without a `server_name` the host part of the address is verified.
+/// The `cert_file` and `key_file` are only needed for mutual TLS.
+///
+/// ```
+/// use nats_bridge::socket::Tls;
+///
+/// let tls = Tls {
+///     ca_file: "/etc/nats/ca.pem".into(),
+///     cert_file: "/etc/nats/client-cert.pem".into(),
+///     key_file: "/etc/nats/client-key.pem".into(),
+///     server_name: "nats.example.com".into(),
+/// };
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Tls {
+    pub ca_file: String,
+    pub cert_file: String,
+    pub key_file: String,
+    pub server_name: String,
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<SslStream<TcpStream>>),
+}
+
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buffer),
+            Stream::Tls(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(data),
+            Stream::Tls(stream) => stream.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
 }
 
 pub struct Socket {
     host: String,
+    servers: Vec<String>,
+    server: usize,
     agent: String,
     connected: bool,
     timeout: u64,
-    stream: TcpStream,
-    reader: BufReader<TcpStream>,
+    tls: Option<Tls>,
+    line: String,
+    reader: BufReader<Stream>,
 }
 
 pub fn log(host: &str, agent: &str, info: &str) {
@@ -31,41 +102,118 @@ pub fn log(host: &str, agent: &str, info: &str) {
 /// # Socket
 ///
 /// The user interface for this library.
+/// The host may be a comma separated list of servers, which are tried
+/// in random order. Reconnects fail over to the next server in the list.
 ///
 /// ```no_run
 /// use nats_bridge::socket::Socket;
 ///
 /// let host = "pubsub.pubnub.com:80";
 /// let mut socket = Socket::new(host, "HTTP Agent", 5);
+///
+/// let cluster = "10.0.0.1:4222,10.0.0.2:4222,10.0.0.3:4222";
+/// let mut socket = Socket::new(cluster, "NATS Agent", 5);
 /// ```
 impl Socket {
     pub fn new(host: &str, agent: &str, timeout: u64) -> Self {
-        let stream = Socket::connect(host, agent, timeout);
+        let mut servers: Vec<String> = host
+            .split(',')
+            .map(str::trim)
+            .filter(|server| !server.is_empty())
+            .map(String::from)
+            .collect();
+        if servers.is_empty() {
+            servers.push(host.into());
+        }
+        shuffle(&mut servers);
+
+        let (server, stream) = Socket::connect(&servers, 0, agent, timeout);
         Self {
-            host: host.into(),
+            host: servers[server].clone(),
+            servers,
+            server,
             agent: agent.into(),
             timeout,
             connected: true,
-            stream: stream.try_clone().expect("Unable to clone stream"),
-            reader: BufReader::new(stream),
+            tls: None,
+            line: String::new(),
+            reader: BufReader::new(Stream::Plain(stream)),
         }
     }
 
+    /// ## TLS Socket
+    ///
+    /// Connect and negotiate TLS straight away, like HTTPS.
+    /// Reconnects negotiate TLS again with the same settings.
+    ///
+    /// ```no_run
+    /// use nats_bridge::socket::{Socket, Tls};
+    ///
+    /// let host = "psdsn.pubnub.com:443";
+    /// let tls = Tls::default();
+    /// let mut socket = Socket::new_tls(host, "HTTP Agent", 5, &tls)
+    ///     .expect("TLS negotiated");
+    /// ```
+    pub fn new_tls(
+        host: &str,
+        agent: &str,
+        timeout: u64,
+        tls: &Tls,
+    ) -> Result<Self, Error> {
+        let mut socket = Socket::new(host, agent, timeout);
+        socket.upgrade(tls)?;
+        socket.tls = Some(tls.clone());
+        Ok(socket)
+    }
+
     pub fn log(&mut self, message: &str) {
         log(&self.host, &self.agent, message);
     }
 
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// ## Server Pool
+    ///
+    /// The current server is `host()`, the others are used on failover.
+    /// Add servers learned at runtime, like cluster members announced by
+    /// a NATS server. Known servers are skipped.
+    ///
+    /// ```no_run
+    /// use nats_bridge::socket::Socket;
+    /// let mut socket = Socket::new("10.0.0.1:4222", "NATS Agent", 5);
+    /// socket.add_servers(&["10.0.0.2:4222".to_string()]);
+    /// assert_eq!(socket.servers().len(), 2);
+    /// ```
+    pub fn add_servers(&mut self, servers: &[String]) {
+        for server in servers {
+            if !self.servers.contains(server) {
+                self.log(&format!("Discovered {}", server));
+                self.servers.push(server.to_string());
+            }
+        }
+    }
+
+    pub fn servers(&self) -> &[String] {
+        &self.servers
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
     pub fn check_reconnect(&mut self) {
         if self.connected {
             return;
         }
         self.reconnect();
-        self.connected = true;
     }
 
     /// ## Write Data
     ///
-    /// Write string data to the stream.
+    /// Write data to the stream.
+    /// Binary data is logged lossily but written as is.
     ///
     /// ```no_run
     /// use nats_bridge::socket::Socket;
@@ -74,23 +222,31 @@ impl Socket {
     /// let request = "GET / HTTP/1.1\r\nHost: pubnub.com\r\n\r\n";
     /// socket.write(request).expect("data written");
     /// ```
-    pub fn write(&mut self, data: impl AsRef<str>) -> Result<usize, Error> {
+    pub fn write(&mut self, data: impl AsRef<[u8]>) -> Result<usize, Error> {
+        // Log Write Output
+        self.log(&String::from_utf8_lossy(data.as_ref()));
+
+        self.send(data.as_ref())
+    }
+
+    /// ## Send Data
+    ///
+    /// Write raw bytes to the stream without logging them.
+    /// Use this for data that must not appear in the logs, like credentials.
+    ///
+    /// ```no_run
+    /// use nats_bridge::socket::Socket;
+    /// let host = "pubsub.pubnub.com:80";
+    /// let mut socket = Socket::new(host, "HTTP Agent", 5);
+    /// let request = b"GET / HTTP/1.1\r\nHost: pubnub.com\r\n\r\n";
+    /// socket.send(request).expect("data written");
+    /// ```
+    pub fn send(&mut self, data: &[u8]) -> Result<usize, Error> {
         // Reconnect if not connected
         self.check_reconnect();
 
-        // Log Write Output
-        self.log(data.as_ref());
-
-        let result = self.stream.write(data.as_ref().as_bytes());
-        match result {
-            Ok(size) => {
-                if size > 0 {
-                    return Ok(size);
-                }
-                self.log("No data has been written.");
-                self.connected = false;
-                Err(Error::Write)
-            }
+        match self.reader.get_mut().write_all(data) {
+            Ok(()) => Ok(data.len()),
             Err(error) => {
                 self.log(&format!("Unwrittable: {}", error));
                 self.log(&format!("Disconnected: {}", error));
@@ -103,6 +259,8 @@ impl Socket {
     /// ## Read Line
     ///
     /// Read a line of data from the stream.
+    /// A read timeout returns `Error::Timeout` and keeps the connection,
+    /// the partial line is kept for the next call.
     ///
     /// ```no_run
     /// use nats_bridge::socket::Socket;
@@ -116,21 +274,70 @@ impl Socket {
         // Reconnect if not connected
         self.check_reconnect();
 
-        let mut line = String::new();
-        let size = match self.reader.read_line(&mut line) {
+        let size = match self.reader.read_line(&mut self.line) {
             Ok(size) => size,
-            Err(_error) => {
+            Err(error) => {
+                if error.kind() == ErrorKind::WouldBlock
+                    || error.kind() == ErrorKind::TimedOut
+                {
+                    return Err(Error::Timeout);
+                }
+                self.line.clear();
                 self.connected = false;
                 return Err(Error::Read);
             }
         };
 
         if size == 0 {
+            self.line.clear();
             self.connected = false;
             return Err(Error::Read);
         }
 
-        Ok(line)
+        Ok(std::mem::take(&mut self.line))
+    }
+
+    /// ## Read Timeout
+    ///
+    /// Change how long reads wait for data, like for a request deadline.
+    /// Reconnecting or `reset_read_timeout()` restores the default.
+    ///
+    /// ```no_run
+    /// use nats_bridge::socket::Socket;
+    /// use std::time::Duration;
+    /// let host = "pubsub.pubnub.com:80";
+    /// let mut socket = Socket::new(host.into(), "HTTP Agent", 5);
+    /// socket.set_read_timeout(Duration::from_millis(250));
+    /// let line = socket.readln();
+    /// socket.reset_read_timeout();
+    /// ```
+    pub fn set_read_timeout(&mut self, timeout: time::Duration) {
+        // A zero duration would mean no timeout at all
+        let timeout = timeout.max(time::Duration::from_millis(1));
+        self.reader
+            .get_ref()
+            .tcp()
+            .set_read_timeout(Some(timeout))
+            .unwrap_or_default();
+    }
+
+    pub fn reset_read_timeout(&mut self) {
+        self.set_read_timeout(time::Duration::new(self.timeout, 0));
+    }
+
+    /// ## Default Timeout
+    ///
+    /// Change the default timeout in seconds, kept across reconnects.
+    ///
+    /// ```no_run
+    /// use nats_bridge::socket::Socket;
+    /// let host = "pubsub.pubnub.com:80";
+    /// let mut socket = Socket::new(host.into(), "HTTP Agent", 5);
+    /// socket.set_timeout(1);
+    /// ```
+    pub fn set_timeout(&mut self, timeout: u64) {
+        self.timeout = timeout;
+        self.reset_read_timeout();
     }
 
     /// ## Read Bytes
@@ -168,9 +375,97 @@ impl Socket {
         Ok(String::from_utf8_lossy(&buffer).to_string())
     }
 
+    /// ## Read Exact Bytes
+    ///
+    /// Read exactly the specified amount of data from the stream,
+    /// waiting for data split across several packets.
+    ///
+    /// ```no_run
+    /// use nats_bridge::socket::Socket;
+    ///
+    /// let host = "pubsub.pubnub.com:80";
+    /// let mut socket = Socket::new(host.into(), "HTTP Agent", 5);
+    /// let request = "GET / HTTP/1.1\r\nHost: pubnub.com\r\n\r\n";
+    /// socket.write(request).expect("data written");
+    /// let data = socket.read_exact(30).expect("data read"); // 30 bytes
+    /// assert_eq!(data.len(), 30);
+    /// ```
+    pub fn read_exact(&mut self, bytes: usize) -> Result<Vec<u8>, Error> {
+        // Reconnect if not connected
+        self.check_reconnect();
+
+        let mut buffer = vec![0u8; bytes];
+        match self.reader.read_exact(&mut buffer) {
+            Ok(()) => Ok(buffer),
+            Err(_error) => {
+                self.connected = false;
+                Err(Error::Read)
+            }
+        }
+    }
+
+    /// ## Upgrade to TLS
+    ///
+    /// Negotiate TLS over the open connection.
+    /// Protocols like NATS exchange a plaintext greeting before the upgrade.
+    /// A reconnect falls back to plaintext so the greeting can be repeated.
+    ///
+    /// ```no_run
+    /// use nats_bridge::socket::{Socket, Tls};
+    /// let host = "0.0.0.0:4222";
+    /// let mut socket = Socket::new(host, "NATS Agent", 5);
+    /// let info = socket.readln().expect("plaintext greeting");
+    /// socket.upgrade(&Tls::default()).expect("TLS negotiated");
+    /// ```
+    pub fn upgrade(&mut self, tls: &Tls) -> Result<(), Error> {
+        let stream = match self.reader.get_ref() {
+            Stream::Plain(stream) => stream.try_clone(),
+            Stream::Tls(_) => return Ok(()),
+        };
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_error) => return Err(Error::Tls),
+        };
+
+        // Verify the configured name or the host part of the address
+        let domain = if tls.server_name.is_empty() {
+            match self.host.rsplit_once(':') {
+                Some((domain, _port)) => domain.to_string(),
+                None => self.host.to_string(),
+            }
+        } else {
+            tls.server_name.to_string()
+        };
+
+        let connector = match tls_connector(tls) {
+            Ok(connector) => connector,
+            Err(error) => {
+                self.log(&format!("TLS Configuration: {}", error));
+                return Err(Error::Tls);
+            }
+        };
+        let configuration = match connector.configure() {
+            Ok(configuration) => configuration,
+            Err(_error) => return Err(Error::Tls),
+        };
+        match configuration.connect(&domain, stream) {
+            Ok(stream) => {
+                self.log("TLS Negotiated");
+                self.reader = BufReader::new(Stream::Tls(Box::new(stream)));
+                Ok(())
+            }
+            Err(error) => {
+                self.log(&format!("TLS Handshake: {}", error));
+                self.connected = false;
+                Err(Error::Tls)
+            }
+        }
+    }
+
     /// ## Disconnect
     ///
     /// This will courteously turn off the connection of your socket.
+    /// The next read or write reconnects.
     ///
     /// ```no_run
     /// use nats_bridge::socket::Socket;
@@ -179,44 +474,138 @@ impl Socket {
     /// socket.disconnect();
     /// ```
     pub fn disconnect(&mut self) {
-        self.stream.shutdown(Shutdown::Both).unwrap_or_default();
+        if let Stream::Tls(stream) = self.reader.get_mut() {
+            let _ = stream.shutdown();
+        }
+        self.reader
+            .get_ref()
+            .tcp()
+            .shutdown(Shutdown::Both)
+            .unwrap_or_default();
+        self.connected = false;
     }
 
     pub fn reconnect(&mut self) {
-        thread::sleep(time::Duration::new(1, 0));
-        self.log("Reconnecting");
-        let stream = Socket::connect(&self.host, &self.agent, self.timeout);
-        self.connected = true;
-        self.stream = stream.try_clone().expect("Unable to clone stream");
-        self.reader = BufReader::new(stream);
+        loop {
+            thread::sleep(time::Duration::new(1, 0));
+            self.log("Reconnecting");
+
+            // Fail over to the next server in the pool
+            let (server, stream) = Socket::connect(
+                &self.servers,
+                self.server + 1,
+                &self.agent,
+                self.timeout,
+            );
+            self.server = server;
+            self.host = self.servers[server].clone();
+            self.connected = true;
+            self.line.clear();
+            self.reader = BufReader::new(Stream::Plain(stream));
+
+            // Never fall back to plaintext when TLS was asked for
+            let tls = match self.tls.clone() {
+                Some(tls) => tls,
+                None => return,
+            };
+            if self.upgrade(&tls).is_ok() {
+                return;
+            }
+        }
     }
 
-    fn connect(ip_port: &str, agent: &str, timeout: u64) -> TcpStream {
+    // Try each server in turn, starting at `first`, until one accepts.
+    // Returns the position of the connected server in the pool.
+    fn connect(
+        servers: &[String],
+        first: usize,
+        agent: &str,
+        timeout: u64,
+    ) -> (usize, TcpStream) {
+        let timeout = time::Duration::new(timeout, 0);
+        let mut attempt = 0;
         loop {
-            let host: String = ip_port.into();
-            let error = match TcpStream::connect(host) {
+            let server = (first + attempt) % servers.len();
+            let ip_port = &servers[server];
+            let error = match dial(ip_port, timeout) {
                 Ok(stream) => {
                     log(ip_port, agent, "Connected");
                     stream
-                        .set_read_timeout(Some(time::Duration::new(
-                            timeout, 0,
-                        )))
+                        .set_read_timeout(Some(timeout))
                         .expect("Set Socket Read Timeout");
                     stream
-                        .set_write_timeout(Some(time::Duration::new(
-                            timeout, 0,
-                        )))
+                        .set_write_timeout(Some(timeout))
                         .expect("Set Socket Write Timeout");
-                    return stream;
+                    return (server, stream);
                 }
                 Err(error) => error,
             };
-
-            // Retry connection until the host becomes available
             log(ip_port, agent, &format!("{}", error));
-            thread::sleep(time::Duration::new(1, 0));
+
+            // Retry connection until a host becomes available
+            attempt += 1;
+            if attempt % servers.len() == 0 {
+                thread::sleep(time::Duration::new(1, 0));
+            }
+        }
+    }
+}
+
+// Connect to the first reachable address of the host
+fn dial(
+    ip_port: &str,
+    timeout: time::Duration,
+) -> std::io::Result<TcpStream> {
+    let mut last_error = ErrorKind::NotFound.into();
+    for address in ip_port.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+// Spread clients across the servers of a cluster
+fn shuffle(servers: &mut [String]) {
+    for last in (1..servers.len()).rev() {
+        let mut bytes = [0u8; 4];
+        if rand_bytes(&mut bytes).is_err() {
+            return;
+        }
+        let pick = u32::from_ne_bytes(bytes) as usize % (last + 1);
+        servers.swap(last, pick);
+    }
+}
+
+fn tls_connector(
+    tls: &Tls,
+) -> Result<SslConnector, openssl::error::ErrorStack> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+
+    // Trusted Certificate Authorities
+    if tls.ca_file.is_empty() {
+        let probe = openssl_probe::probe();
+        if probe.cert_file.is_some() || probe.cert_dir.is_some() {
+            builder.load_verify_locations(
+                probe.cert_file.as_deref(),
+                probe.cert_dir.as_deref(),
+            )?;
         }
+    } else {
+        builder.set_ca_file(&tls.ca_file)?;
+    }
+
+    // Client Certificate for Mutual TLS
+    if !tls.cert_file.is_empty() {
+        builder.set_certificate_chain_file(&tls.cert_file)?;
     }
+    if !tls.key_file.is_empty() {
+        builder.set_private_key_file(&tls.key_file, SslFiletype::PEM)?;
+        builder.check_private_key()?;
+    }
+
+    Ok(builder.build())
 }
 
 #[cfg(test)]
@@ -225,7 +614,7 @@ mod socket_tests {
 
     #[test]
     fn write_ok() {
-        let host = "www.pubnub.com:80".into();
+        let host = "www.pubnub.com:80";
         let mut socket = Socket::new(host, "HTTP Agent", 5);
 
         let request = "GET / HTTP/1.1\r\nHost: pubnub.com\r\n\r\n";
@@ -234,7 +623,7 @@ mod socket_tests {
 
     #[test]
     fn read_ok() {
-        let host = "www.pubnub.com:80".into();
+        let host = "www.pubnub.com:80";
         let mut socket = Socket::new(host, "HTTP Agent", 5);
 
         let request = "GET / HTTP/1.1\r\nHost: pubnub.com\r\n\r\n";
@@ -244,12 +633,12 @@ mod socket_tests {
         assert!(result.is_ok());
 
         let data = result.expect("data");
-        assert!(data.len() > 0);
+        assert!(!data.is_empty());
 
         let result = socket.readln();
         assert!(result.is_ok());
 
         let data = result.expect("data");
-        assert!(data.len() > 0);
+        assert!(!data.is_empty());
     }
 }
@@ -0,0 +1,172 @@
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+// NATS Subjects and PubNub Channels
+// Subjects are tokens separated by `.`, subscriptions may use `*` for any
+// single token and `>` for one or more tokens at the end.
+// PubNub channels may end with `.*` to subscribe to every channel below
+// the prefix, up to three levels deep.
+// =-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
+
+// Most PubNub channel characters a name may hold
+const MAX_CHANNEL_LENGTH: usize = 92;
+
+// Tokens of a subject, separated by `.`
+fn tokens(subject: &str) -> impl Iterator<Item = &str> {
+    subject.split('.')
+}
+
+/// ## Valid Subject
+///
+/// A subject to publish to, without wildcards, empty tokens or whitespace.
+///
+/// ```
+/// use nats_bridge::subject;
+///
+/// assert!(subject::is_valid("orders.new"));
+/// assert!(!subject::is_valid("orders.*"));
+/// assert!(!subject::is_valid("orders new"));
+/// ```
+pub fn is_valid(subject: &str) -> bool {
+    tokens(subject).all(|token| valid_token(token) && !is_wildcard(token))
+}
+
+/// ## Valid Subscription Subject
+///
+/// A subject to subscribe to, `*` and `>` must be whole tokens
+/// and `>` must be the last one.
+///
+/// ```
+/// use nats_bridge::subject;
+///
+/// assert!(subject::is_valid_pattern("orders.*.new"));
+/// assert!(subject::is_valid_pattern("orders.>"));
+/// assert!(!subject::is_valid_pattern("orders.>.new"));
+/// assert!(!subject::is_valid_pattern("orders.ne*"));
+/// ```
+pub fn is_valid_pattern(subject: &str) -> bool {
+    let mut tokens = tokens(subject).peekable();
+    while let Some(token) = tokens.next() {
+        if !valid_token(token) || (token == ">" && tokens.peek().is_some()) {
+            return false;
+        }
+    }
+    true
+}
+
+/// ## Valid PubNub Channel
+///
+/// A channel to publish to. Channels are sent as is in the request URL,
+/// so whitespace and URL delimiters are rejected along with the
+/// characters PubNub reserves.
+///
+/// ```
+/// use nats_bridge::subject;
+///
+/// assert!(subject::is_valid_channel("channels.orders"));
+/// assert!(!subject::is_valid_channel("channels.*"));
+/// assert!(!subject::is_valid_channel("channels:orders"));
+/// ```
+pub fn is_valid_channel(channel: &str) -> bool {
+    !channel.is_empty()
+        && channel.chars().count() <= MAX_CHANNEL_LENGTH
+        && !channel.chars().any(reserved_channel_char)
+        && channel.split('.').all(|token| !token.is_empty())
+}
+
+/// ## Valid PubNub Subscription Channel
+///
+/// A channel to subscribe to, which may end with a `.*` wildcard
+/// below a prefix of at most two tokens.
+///
+/// ```
+/// use nats_bridge::subject;
+///
+/// assert!(subject::is_valid_channel_pattern("channels.*"));
+/// assert!(subject::is_valid_channel_pattern("channels.orders"));
+/// assert!(!subject::is_valid_channel_pattern("a.b.c.*"));
+/// assert!(!subject::is_valid_channel_pattern("*"));
+/// ```
+pub fn is_valid_channel_pattern(channel: &str) -> bool {
+    match channel.strip_suffix(".*") {
+        Some(prefix) => {
+            is_valid_channel(prefix) && tokens(prefix).count() <= 2
+        }
+        None => is_valid_channel(channel),
+    }
+}
+
+// Tokens are never empty and hold no whitespace, `*` and `>` may only
+// appear as the whole token
+fn valid_token(token: &str) -> bool {
+    !token.is_empty()
+        && !token.chars().any(char::is_whitespace)
+        && (is_wildcard(token) || !token.contains(&['*', '>'][..]))
+}
+
+fn is_wildcard(token: &str) -> bool {
+    token == "*" || token == ">"
+}
+
+fn reserved_channel_char(character: char) -> bool {
+    character.is_whitespace()
+        || character.is_control()
+        || ",:*/\\?#%".contains(character)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subject_ok() {
+        assert!(is_valid("orders"));
+        assert!(is_valid("_INBOX.2bOSjvkI7uBN5gBlCqHfJp"));
+        assert!(is_valid("$JS.API.CONSUMER.MSG.NEXT.ORDERS.bridge"));
+    }
+
+    #[test]
+    fn subject_invalid() {
+        for subject in
+            ["", "orders.", ".orders", "a..b", "a b", "a\tb", "a\r\n"]
+        {
+            assert!(!is_valid(subject), "{:?}", subject);
+            assert!(!is_valid_pattern(subject), "{:?}", subject);
+        }
+        for subject in ["*", ">", "orders.*", "orders.>", "a*.b", "a.b>"] {
+            assert!(!is_valid(subject), "{:?}", subject);
+        }
+    }
+
+    #[test]
+    fn pattern_ok() {
+        for subject in [">", "*", "orders.*", "*.new", "orders.*.eu.>"] {
+            assert!(is_valid_pattern(subject), "{:?}", subject);
+        }
+        for subject in [">.orders", "orders.>.>", "orders.n*w", "orders.>x"] {
+            assert!(!is_valid_pattern(subject), "{:?}", subject);
+        }
+    }
+
+    #[test]
+    fn channel_ok() {
+        assert!(is_valid_channel("demo"));
+        assert!(is_valid_channel("channels.orders.new"));
+        assert!(is_valid_channel("replies.device-1"));
+        assert!(is_valid_channel(&"x".repeat(MAX_CHANNEL_LENGTH)));
+
+        for channel in ["", "a.", "a,b", "a:b", "a/b", "a\\b", "a b", "a?b"] {
+            assert!(!is_valid_channel(channel), "{:?}", channel);
+        }
+        assert!(!is_valid_channel(&"x".repeat(MAX_CHANNEL_LENGTH + 1)));
+    }
+
+    #[test]
+    fn channel_pattern_ok() {
+        assert!(is_valid_channel_pattern("channels.*"));
+        assert!(is_valid_channel_pattern("channels.orders.*"));
+        assert!(is_valid_channel_pattern("channels"));
+
+        for channel in ["*", ".*", "a.b.c.*", "a.*.b", "a*", "a.*.*"] {
+            assert!(!is_valid_channel_pattern(channel), "{:?}", channel);
+        }
+    }
+}